@@ -1,3 +1,283 @@
 //! CloudMon metrics processor API
 //!
+use axum::{
+    body::Body,
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    Router,
+};
+use serde_json::json;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tower::{BoxError, ServiceBuilder};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::types::SharedState;
+
 pub mod v1;
+
+/// Request id assigned to the request (`x-request-id` header)
+pub fn get_request_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get("x-request-id").and_then(|x| x.to_str().ok())
+}
+
+/// Build CORS layer allowing requests from the given origins (`*` allows any). `None` is
+/// returned when no origins are configured.
+pub fn get_cors_layer(origins: &[String]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|x| x == "*") {
+        AllowOrigin::any()
+    } else {
+        // Origins are validated when loading the configuration
+        AllowOrigin::list(origins.iter().filter_map(|x| HeaderValue::from_str(x).ok()))
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE]),
+    )
+}
+
+/// Abort requests not completed within `timeout` with 504 Gateway Timeout
+pub fn with_request_timeout(router: Router<SharedState>, timeout: Duration) -> Router<SharedState> {
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(|_: BoxError| async {
+                (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    Json(json!({"message": "Request timed out"})),
+                )
+            }))
+            .timeout(timeout),
+    )
+}
+
+/// Reject requests with a body larger than `limit` bytes with 413 Payload Too Large
+pub fn with_body_limit(router: Router<SharedState>, limit: usize) -> Router<SharedState> {
+    router
+        .layer(middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| {
+                let length = request
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(|x| x.parse::<usize>().ok());
+                async move {
+                    match length {
+                        Some(length) if length > limit => (
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            Json(json!({"message": "Request body is too large"})),
+                        )
+                            .into_response(),
+                        _ => next.run(request).await,
+                    }
+                }
+            },
+        ))
+        // Bodies without content-length are limited while being read
+        .layer(DefaultBodyLimit::max(limit))
+}
+
+/// Paths never requiring the token so that liveness and readiness probes keep working
+const UNPROTECTED_PATHS: [&str; 2] = ["/live", "/ready"];
+
+/// Require `Authorization: Bearer <server.api_token>` on the routes of the router. Requests pass
+/// unchecked when no token is configured.
+pub fn with_api_token_auth(router: Router<SharedState>, state: SharedState) -> Router<SharedState> {
+    router.route_layer(middleware::from_fn_with_state(state, require_api_token))
+}
+
+/// Middleware verifying the bearer token of the request
+async fn require_api_token<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let expected = state.read().await.config.server.api_token.clone();
+    if let Some(token) = expected {
+        let provided = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        // Compare in constant time not to leak the token through the response timing
+        let valid = provided.is_some_and(|x| bool::from(x.as_bytes().ct_eq(token.as_bytes())));
+        if !valid && !UNPROTECTED_PATHS.contains(&request.uri().path()) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"message": "Unauthorized"})),
+            )
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    async fn get_status(app: axum::Router, uri: &str, token: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri(uri);
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_api_token() {
+        let state = test_utils::get_test_state("http://localhost:1");
+        state.write().await.config.server.api_token = Some("secret".to_string());
+        let app = axum::Router::new()
+            .nest(
+                "/api/v1",
+                api::with_api_token_auth(api::v1::get_v1_routes(), state.clone()),
+            )
+            .with_state(state);
+
+        assert_eq!(
+            get_status(app.clone(), "/api/v1/services", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get_status(app.clone(), "/api/v1/services", Some("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get_status(app.clone(), "/api/v1/services", Some("secret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(get_status(app, "/api/v1/live", None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_api_token_not_configured() {
+        let state = test_utils::get_test_state("http://localhost:1");
+        let app =
+            api::with_api_token_auth(api::v1::get_v1_routes(), state.clone()).with_state(state);
+
+        assert_eq!(get_status(app, "/services", None).await, StatusCode::OK);
+    }
+
+    async fn preflight(app: axum::Router, origin: &str) -> Option<String> {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("OPTIONS")
+                    .uri("/api/v1/services")
+                    .header("origin", origin)
+                    .header("access-control-request-method", "GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .map(|x| x.to_str().unwrap().to_string())
+    }
+
+    fn get_cors_app(origins: &[&str]) -> axum::Router {
+        let state = test_utils::get_test_state("http://localhost:1");
+        let origins: Vec<String> = origins.iter().map(|x| x.to_string()).collect();
+        let mut app = axum::Router::new()
+            .nest("/api/v1", api::v1::get_v1_routes())
+            .with_state(state);
+        if let Some(cors) = api::get_cors_layer(&origins) {
+            app = app.layer(cors);
+        }
+        app
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        assert_eq!(
+            preflight(get_cors_app(&["*"]), "https://grafana.example.com").await,
+            Some("*".to_string())
+        );
+        let app = get_cors_app(&["https://grafana.example.com", "https://other"]);
+        assert_eq!(
+            preflight(app.clone(), "https://grafana.example.com").await,
+            Some("https://grafana.example.com".to_string())
+        );
+        assert_eq!(preflight(app, "https://evil.example.com").await, None);
+        assert_eq!(
+            preflight(get_cors_app(&[]), "https://grafana.example.com").await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // Graphite answering slower than the request timeout
+        let graphite = axum::Router::new().route(
+            "/render",
+            axum::routing::get(|| async {
+                tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+                "[]"
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(graphite.into_make_service()),
+        );
+
+        let state = test_utils::get_test_state(url.as_str());
+        let app = api::with_request_timeout(
+            api::v1::get_v1_routes(),
+            std::time::Duration::from_millis(500),
+        )
+        .with_state(state);
+        let start = std::time::Instant::now();
+        let status = get_status(
+            app,
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min",
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let state = test_utils::get_test_state("http://localhost:1");
+        let app = api::with_body_limit(graphite::get_graphite_routes(), 16).with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/render")
+                    .header("content-type", "application/x-www-form-urlencoded")
+                    .header("content-length", "41")
+                    .body(Body::from("target=flag.env1.srvA.metric-1&from=-5min"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -4,20 +4,38 @@
 //!
 use axum::{
     extract::Query,
+    extract::RawQuery,
     extract::State,
-    http::StatusCode,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+        HeaderMap, StatusCode,
+    },
     response::{IntoResponse, Json, Response},
-    routing::get,
+    routing::{get, post},
     Router,
 };
+use futures::stream::{self, StreamExt};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::time::timeout;
 
-use crate::common::get_service_health;
-use crate::types::{AppState, CloudMonError, ServiceHealthData};
+use crate::api::get_request_id;
+use crate::common::{
+    explain_service_health, get_service_flags, get_service_health, parse_time_spec,
+};
+use crate::config::Config;
+use crate::graphite::probe_datasource;
+use crate::types::{
+    AppState, CloudMonError, HealthExplanation, ServiceHealthData, ServiceHealthPoint, SharedState,
+};
 
-/// Query parameters supported by the /health API call
-#[derive(Debug, Deserialize)]
+/// Query parameters supported by the /health API call (also an item of the /health/batch
+/// request)
+#[derive(Debug, Deserialize, Serialize)]
 pub struct HealthQuery {
     /// Start point to query metrics
     pub from: String,
@@ -26,6 +44,103 @@ pub struct HealthQuery {
     pub max_data_points: u32,
     pub service: String,
     pub environment: String,
+    /// Seconds after which the evaluation is aborted (the datasource timeout still applies)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Collapse the health series into a single point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollup: Option<HealthRollup>,
+    /// Width of the buckets the health series is downsampled into (worst value per bucket)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution_secs: Option<u64>,
+}
+
+/// Query parameters supported by the /explain API call
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExplainQuery {
+    pub service: String,
+    pub environment: String,
+    /// Explained time (RFC3339, `now` or relative like `-5min`)
+    pub at: String,
+}
+
+/// Aggregation of the health series into a single point
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthRollup {
+    /// Worst value of the window
+    Max,
+    /// Most recent value
+    Last,
+    /// Rounded average value of the window
+    Avg,
+}
+
+/// Collapse health datapoints into a single point using the rollup aggregation.
+///
+/// The latest point with the aggregated value is returned so that its triggered metrics describe
+/// it. When no point has the (average) value the latest point is returned with the value
+/// replaced. Empty series stay empty.
+pub fn rollup_health_data(data: ServiceHealthData, rollup: HealthRollup) -> ServiceHealthData {
+    let Some(last) = data.last() else {
+        return data;
+    };
+    let value = match rollup {
+        HealthRollup::Last => last.value,
+        HealthRollup::Max => data.iter().map(|x| x.value).max().unwrap_or_default(),
+        HealthRollup::Avg => {
+            let sum: u32 = data.iter().map(|x| x.value as u32).sum();
+            (sum as f64 / data.len() as f64).round() as u8
+        }
+    };
+    let point = match data.iter().rev().find(|x| x.value == value) {
+        Some(point) => point.clone(),
+        None => ServiceHealthPoint {
+            value,
+            ..last.clone()
+        },
+    };
+    vec![point]
+}
+
+/// Downsample health datapoints into buckets of `resolution_secs` keeping the worst value of
+/// every bucket.
+///
+/// Buckets start at the first datapoint and are widened when more than `max_data_points` of
+/// them would be needed. The latest point with the worst value describes the bucket and is
+/// returned with the timestamp of the bucket start.
+pub fn downsample_health_data(
+    data: ServiceHealthData,
+    resolution_secs: u64,
+    max_data_points: u32,
+) -> ServiceHealthData {
+    let (Some(first), Some(last)) = (data.first(), data.last()) else {
+        return data;
+    };
+    let start = first.ts as u64;
+    let span = (last.ts as u64).saturating_sub(start);
+    let width = resolution_secs
+        .max(1)
+        .max(span / (max_data_points.max(1) as u64) + 1);
+    let mut result: ServiceHealthData = Vec::new();
+    for point in data.iter() {
+        let bucket = (start + (point.ts as u64).saturating_sub(start) / width * width) as u32;
+        match result.last_mut() {
+            Some(current) if current.ts == bucket => {
+                if point.value >= current.value {
+                    *current = ServiceHealthPoint {
+                        ts: bucket,
+                        ..point.clone()
+                    };
+                }
+            }
+            _ => result.push(ServiceHealthPoint {
+                ts: bucket,
+                ..point.clone()
+            }),
+        }
+    }
+    result
 }
 
 fn default_max_data_points() -> u32 {
@@ -33,7 +148,13 @@ fn default_max_data_points() -> u32 {
 }
 
 /// Response of the /health API call
-#[derive(Debug, Serialize, Deserialize)]
+///
+/// `metrics` is sent as the `[ts, value]` series, triggered metrics, their values and the matched
+/// expression of the points go to the separate `details` list. Unknown fields are ignored and
+/// fields not essential for the reporter have defaults, so that convertor and reporter of
+/// different versions understand each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "ServiceHealthResponseData", from = "ServiceHealthResponseData")]
 pub struct ServiceHealthResponse {
     pub name: String,
     pub service_category: String,
@@ -41,17 +162,159 @@ pub struct ServiceHealthResponse {
     pub metrics: ServiceHealthData,
 }
 
+/// Details of a health datapoint, reported next to the `[ts, value]` series
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HealthPointDetails {
+    pub ts: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub triggered: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched_expression: Option<String>,
+}
+
+/// Wire format of [`ServiceHealthResponse`]
+#[derive(Debug, Serialize, Deserialize)]
+struct ServiceHealthResponseData {
+    name: String,
+    #[serde(default)]
+    service_category: String,
+    environment: String,
+    #[serde(default)]
+    metrics: Vec<(u32, u8)>,
+    /// Details of the points with triggered metrics or a matched expression
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    details: Vec<HealthPointDetails>,
+}
+
+impl From<ServiceHealthResponse> for ServiceHealthResponseData {
+    fn from(rsp: ServiceHealthResponse) -> Self {
+        let metrics = rsp.metrics.iter().map(|x| (x.ts, x.value)).collect();
+        let details = rsp
+            .metrics
+            .into_iter()
+            .filter(|x| {
+                !x.triggered.is_empty() || !x.values.is_empty() || x.matched_expression.is_some()
+            })
+            .map(|x| HealthPointDetails {
+                ts: x.ts,
+                triggered: x.triggered,
+                values: x.values,
+                matched_expression: x.matched_expression,
+            })
+            .collect();
+        Self {
+            name: rsp.name,
+            service_category: rsp.service_category,
+            environment: rsp.environment,
+            metrics,
+            details,
+        }
+    }
+}
+
+impl From<ServiceHealthResponseData> for ServiceHealthResponse {
+    fn from(data: ServiceHealthResponseData) -> Self {
+        let mut details: HashMap<u32, HealthPointDetails> =
+            data.details.into_iter().map(|x| (x.ts, x)).collect();
+        let metrics = data
+            .metrics
+            .into_iter()
+            .map(|(ts, value)| {
+                let details = details.remove(&ts).unwrap_or_default();
+                ServiceHealthPoint {
+                    ts,
+                    value,
+                    triggered: details.triggered,
+                    values: details.values,
+                    matched_expression: details.matched_expression,
+                }
+            })
+            .collect();
+        Self {
+            name: data.name,
+            service_category: data.service_category,
+            environment: data.environment,
+            metrics,
+        }
+    }
+}
+
+/// Flag metric states in the /flags response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlagMetricResponse {
+    pub metric: String,
+    pub datapoints: Vec<(u32, bool)>,
+}
+
+/// Response of the /flags API call
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceFlagsResponse {
+    pub name: String,
+    pub environment: String,
+    pub flags: Vec<FlagMetricResponse>,
+}
+
+/// Per service entry of the multi-service /health response
+///
+/// `Err` is tried first while deserializing since the lenient `ServiceHealthResponse` would
+/// accept error entries as well.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ServiceHealthResult {
+    Err {
+        name: String,
+        environment: String,
+        message: String,
+    },
+    Ok(ServiceHealthResponse),
+}
+
+/// Response of the /explain API call
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceExplainResponse {
+    pub name: String,
+    pub environment: String,
+    #[serde(flatten)]
+    pub explanation: HealthExplanation,
+}
+
+/// Configured service in the /services response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceInfo {
+    pub name: String,
+    pub category: String,
+    pub component_name: Option<String>,
+    /// Environments in which the flag metrics of the service are available
+    pub environments: Vec<String>,
+}
+
+/// Response of the /config/validate API call
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigValidationResponse {
+    pub ok: bool,
+    pub errors: Vec<String>,
+}
+
 /// Construct supported api v1 routes
-pub fn get_v1_routes() -> Router<AppState> {
-    return Router::new()
+pub fn get_v1_routes() -> Router<SharedState> {
+    Router::new()
         .route("/", get(root))
         .route("/info", get(info))
-        .route("/health", get(handler_health));
+        .route("/live", get(live))
+        .route("/ready", get(ready))
+        .route("/health", get(handler_health))
+        .route("/health/batch", post(handler_health_batch))
+        .route("/flags", get(handler_flags))
+        .route("/explain", get(handler_explain))
+        .route("/services", get(handler_services))
+        .route("/config/validate", post(handler_config_validate))
 }
 
 /// Return API v1 root info
 async fn root() -> impl IntoResponse {
-    return (StatusCode::OK, Json(json!({"name": "v1"})));
+    (StatusCode::OK, Json(json!({"name": "v1"})))
 }
 
 /// Return v1 API infos
@@ -59,53 +322,1210 @@ async fn info() -> impl IntoResponse {
     (StatusCode::OK, "V1 API of the CloudMon\n")
 }
 
+/// Liveness probe: the process is up and serving requests
+async fn live() -> impl IntoResponse {
+    (StatusCode::OK, Json(json!({"status": "ok"})))
+}
+
+/// Readiness probe: the configured datasource is reachable
+async fn ready(State(state): State<SharedState>) -> impl IntoResponse {
+    let (client, url) = {
+        let state = state.read().await;
+        (
+            state.req_client.clone(),
+            state.config.datasource.url.clone(),
+        )
+    };
+    match probe_datasource(&client, url.as_str()).await {
+        Ok(()) => (StatusCode::OK, Json(json!({"status": "ok"}))),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status": "unavailable", "message": "Datasource is not reachable"})),
+        ),
+    }
+}
+
+/// Evaluate health of a single service for the /health query
+async fn get_service_health_response(
+    state: &AppState,
+    service: &str,
+    query: &HealthQuery,
+    request_id: Option<&str>,
+) -> Result<ServiceHealthResponse, CloudMonError> {
+    let hm_config = state
+        .health_metrics
+        .get(service)
+        .ok_or(CloudMonError::ServiceNotSupported)?;
+    if let Some(ref telemetry) = state.telemetry {
+        // Only configured values become label values to keep the number of series bounded
+        let environment = match state
+            .environments
+            .iter()
+            .any(|x| x.name == query.environment)
+            || state.environment_aliases.contains_key(&query.environment)
+        {
+            true => query.environment.as_str(),
+            false => "unknown",
+        };
+        telemetry
+            .health_requests
+            .with_label_values(&[service, environment])
+            .inc();
+    }
+    let health = get_service_health(
+        state,
+        service,
+        query.environment.as_str(),
+        query.from.as_str(),
+        query.to.as_str(),
+        query.max_data_points as u16,
+        request_id,
+    );
+    let mut health_data = match query.timeout_secs {
+        Some(secs) => timeout(Duration::from_secs(secs), health)
+            .await
+            .map_err(|_| CloudMonError::Timeout)??,
+        None => health.await?,
+    };
+    if let Some(resolution_secs) = query.resolution_secs {
+        health_data = downsample_health_data(health_data, resolution_secs, query.max_data_points);
+    }
+    if let Some(rollup) = query.rollup {
+        health_data = rollup_health_data(health_data, rollup);
+    }
+    Ok(ServiceHealthResponse {
+        name: service.to_string(),
+        service_category: hm_config.category.clone(),
+        environment: query.environment.clone(),
+        metrics: health_data,
+    })
+}
+
+/// Parse the query of the /health request. Repeated `service` parameters are joined into a
+/// comma separated list.
+fn parse_health_query(raw_query: &str) -> Result<HealthQuery, serde_urlencoded::de::Error> {
+    let mut services: Vec<String> = Vec::new();
+    let mut params: Vec<(String, String)> = serde_urlencoded::from_str(raw_query)?;
+    params.retain(|(key, value)| match key.as_str() {
+        "service" => {
+            services.push(value.clone());
+            false
+        }
+        _ => true,
+    });
+    if !services.is_empty() {
+        params.push(("service".to_string(), services.join(",")));
+    }
+    serde_urlencoded::from_str(
+        serde_urlencoded::to_string(params)
+            .unwrap_or_default()
+            .as_str(),
+    )
+}
+
 /// Handler method invoked for /health request
-pub async fn handler_health(query: Query<HealthQuery>, State(state): State<AppState>) -> Response {
-    tracing::debug!("Processing query {:?}", query);
-    match state.health_metrics.get(&query.service) {
-        Some(hm_config) => {
-            // We have health metric configuration
-            match get_service_health(
-                &state,
-                query.service.as_str(),
-                query.environment.as_str(),
-                query.from.as_str(),
-                query.to.as_str(),
-                query.max_data_points as u16,
+///
+/// `service` may contain a comma separated list of services (or be repeated). In this case an
+/// array with an entry per service is returned and failures are reported per entry. Empty
+/// service names are ignored.
+pub async fn handler_health(
+    RawQuery(raw_query): RawQuery,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Response, CloudMonError> {
+    let query = match parse_health_query(raw_query.as_deref().unwrap_or_default()) {
+        Ok(query) => query,
+        Err(e) => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                format!("Failed to deserialize query string: {}", e),
             )
-            .await
+                .into_response())
+        }
+    };
+    tracing::debug!("Processing query {:?}", query);
+    validate_time_range(&query)?;
+    let state = state.read().await;
+    let services: Vec<&str> = query
+        .service
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect();
+    if let [service] = services[..] {
+        if !state.health_metrics.contains_key(service) {
+            // Requested service is not known
+            return Err(CloudMonError::ServiceNotSupported);
+        }
+        let rsp =
+            get_service_health_response(&state, service, &query, get_request_id(&headers)).await?;
+        return Ok(cached_json_response(
+            &headers,
+            state.config.server.health_cache_max_age_secs,
+            &rsp,
+        ));
+    }
+    if services.is_empty() {
+        return Err(CloudMonError::ServiceNotSupported);
+    }
+    let mut results: Vec<ServiceHealthResult> = Vec::new();
+    for service in services {
+        results.push(
+            match get_service_health_response(&state, service, &query, get_request_id(&headers))
+                .await
             {
-                Ok(health_data) => (
-                    StatusCode::OK,
-                    Json(ServiceHealthResponse {
-                        name: query.service.clone(),
-                        service_category: hm_config.category.clone(),
-                        environment: query.environment.clone(),
-                        metrics: health_data,
-                    }),
-                )
-                    .into_response(),
-                Err(error) => match error {
-                    CloudMonError::EnvNotSupported | CloudMonError::ServiceNotSupported => (
-                        StatusCode::CONFLICT,
-                        Json(json!({ "message": format!("{}", error) })),
-                    )
-                        .into_response(),
-                    _ => (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({ "message": format!("{}", error) })),
-                    )
-                        .into_response(),
+                Ok(rsp) => ServiceHealthResult::Ok(rsp),
+                Err(error) => ServiceHealthResult::Err {
+                    name: service.to_string(),
+                    environment: query.environment.clone(),
+                    message: format!("{}", error),
                 },
-            }
+            },
+        );
+    }
+    Ok(cached_json_response(
+        &headers,
+        state.config.server.health_cache_max_age_secs,
+        &results,
+    ))
+}
+
+/// Evaluate single item of the /health/batch request
+async fn get_health_batch_result(
+    state: &AppState,
+    query: HealthQuery,
+    request_id: Option<&str>,
+) -> ServiceHealthResult {
+    let res = match validate_time_range(&query) {
+        Ok(()) => {
+            get_service_health_response(state, query.service.as_str(), &query, request_id).await
         }
-        _ => {
-            // Requested service is not known
+        Err(error) => Err(error),
+    };
+    match res {
+        Ok(rsp) => ServiceHealthResult::Ok(rsp),
+        Err(error) => ServiceHealthResult::Err {
+            name: query.service,
+            environment: query.environment,
+            message: format!("{}", error),
+        },
+    }
+}
+
+/// Handler method invoked for /health/batch request
+///
+/// Every item of the request is evaluated like a single /health query. Results are returned
+/// in the order of the request items with failures reported per item. Batches larger than
+/// `server.health_batch_max_items` are rejected with 413.
+pub async fn handler_health_batch(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(queries): Json<Vec<HealthQuery>>,
+) -> Response {
+    tracing::debug!("Processing batch of {} health queries", queries.len());
+    let state = state.read().await;
+    let max_items = state.config.server.health_batch_max_items;
+    if queries.len() > max_items {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({"message": format!("Batch exceeds {} items", max_items)})),
+        )
+            .into_response();
+    }
+    let results: Vec<ServiceHealthResult> = stream::iter(queries)
+        .map(|query| get_health_batch_result(&state, query, get_request_id(&headers)))
+        .buffered(state.config.server.health_batch_concurrency.max(1))
+        .collect()
+        .await;
+    (StatusCode::OK, Json(results)).into_response()
+}
+
+/// JSON response with `Cache-Control` and weak `ETag` headers. `304 Not Modified` is returned
+/// when the `If-None-Match` request header matches the ETag. Plain JSON is returned with
+/// `max_age` of 0.
+fn cached_json_response<T: Serialize>(headers: &HeaderMap, max_age: u32, body: &T) -> Response {
+    if max_age == 0 {
+        return (StatusCode::OK, Json(body)).into_response();
+    }
+    let data = match serde_json::to_vec(body) {
+        Ok(data) => data,
+        Err(e) => {
+            return CloudMonError::InternalError(format!("Cannot serialize response: {}", e))
+                .into_response()
+        }
+    };
+    // Stable across restarts and instances, unlike the std hasher
+    let digest = Sha256::digest(&data);
+    let etag: String = digest[..16].iter().map(|x| format!("{:02x}", x)).collect();
+    let etag = format!("W/\"{}\"", etag);
+    let cache_headers = [
+        (CACHE_CONTROL, format!("max-age={}", max_age)),
+        (ETAG, etag.clone()),
+    ];
+    let matches = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| {
+            x.split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+        })
+        .unwrap_or(false);
+    if matches {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (
+        StatusCode::OK,
+        cache_headers,
+        [(CONTENT_TYPE, "application/json".to_string())],
+        data,
+    )
+        .into_response()
+}
+
+/// Handler method invoked for /flags request
+pub async fn handler_flags(
+    query: Query<HealthQuery>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<ServiceFlagsResponse>, CloudMonError> {
+    tracing::debug!("Processing query {:?}", query);
+    validate_time_range(&query)?;
+    let state = state.read().await;
+    let flags = get_service_flags(
+        &state,
+        query.service.as_str(),
+        query.environment.as_str(),
+        query.from.as_str(),
+        query.to.as_str(),
+        query.max_data_points as u16,
+        get_request_id(&headers),
+    )
+    .await?;
+    Ok(Json(ServiceFlagsResponse {
+        name: query.service.clone(),
+        environment: query.environment.clone(),
+        flags: flags
+            .into_iter()
+            .map(|(metric, datapoints)| FlagMetricResponse { metric, datapoints })
+            .collect(),
+    }))
+}
+
+/// Handler method invoked for /explain request
+///
+/// Debug view of the health evaluation at a single datapoint: every expression with its result
+/// and the metric context.
+pub async fn handler_explain(
+    query: Query<ExplainQuery>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<ServiceExplainResponse>, CloudMonError> {
+    tracing::debug!("Processing query {:?}", query);
+    let at = parse_time_spec(query.at.as_str())?;
+    let state = state.read().await;
+    let explanation = explain_service_health(
+        &state,
+        query.service.as_str(),
+        query.environment.as_str(),
+        at,
+        get_request_id(&headers),
+    )
+    .await?;
+    Ok(Json(ServiceExplainResponse {
+        name: query.service.clone(),
+        environment: query.environment.clone(),
+        explanation,
+    }))
+}
+
+/// Handler method invoked for /services request
+pub async fn handler_services(State(state): State<SharedState>) -> impl IntoResponse {
+    let state = state.read().await;
+    let services: Vec<ServiceInfo> = state
+        .health_metrics
+        .iter()
+        .sorted_by(|a, b| a.0.cmp(b.0))
+        .map(|(name, hm)| ServiceInfo {
+            name: name.clone(),
+            category: hm.category.clone(),
+            component_name: hm.component_name.clone(),
+            environments: hm
+                .metrics
+                .iter()
+                .filter_map(|metric| state.flag_metrics.get(metric))
+                .flat_map(|envs| envs.keys().cloned())
+                .collect::<BTreeSet<String>>()
+                .into_iter()
+                .collect(),
+        })
+        .collect();
+    (StatusCode::OK, Json(services))
+}
+
+/// Handler method invoked for /config/validate request
+///
+/// Parses the YAML request body as configuration and processes it the same way as on startup
+/// without touching the running state.
+pub async fn handler_config_validate(body: String) -> impl IntoResponse {
+    let errors = match Config::from_config_str(body.as_str()) {
+        Ok(config) => AppState::validate_config(config),
+        Err(e) => vec![e.to_string()],
+    };
+    (
+        StatusCode::OK,
+        Json(ConfigValidationResponse {
+            ok: errors.is_empty(),
+            errors,
+        }),
+    )
+}
+
+/// Ensure `from` and `to` of the query are understandable time specifications
+fn validate_time_range(query: &HealthQuery) -> Result<(), CloudMonError> {
+    parse_time_spec(query.from.as_str())?;
+    parse_time_spec(query.to.as_str())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::{get_json, get_test_state, get_test_state_with};
+    use crate::*;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_health_inline_flag_metric() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Regex(
+                "target=alias%28inline%28env1.srvA%29".to_string(),
+            ))
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[5.0, 100], [15.0, 160]]}])
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_test_state_with(server.url().as_str(), |config| {
+            let metric = &mut config.flag_metrics[0];
+            metric.template = None;
+            metric.query = Some("inline($environment.$service)".to_string());
+            metric.op = Some(types::CmpType::Gt);
+            metric.threshold = Some(10.0);
+        })
+        .unwrap();
+        let app = api::v1::get_v1_routes().with_state(Arc::new(RwLock::new(state)));
+
+        let (status, body) = get_json(
+            app,
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        mock.assert_async().await;
+        assert_eq!(body["metrics"], json!([[100, 0], [160, 1]]));
+    }
+
+    #[tokio::test]
+    async fn test_health_environment_alias() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Regex(
+                "target=alias%28dummy1%28env1.srvA.count%29".to_string(),
+            ))
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[95.0, 100], [15.0, 160]]}])
+                    .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let state = get_test_state_with(server.url().as_str(), |config| {
+            config.environments[0].aliases = vec!["production_eu-de".to_string()]
+        })
+        .unwrap();
+        let app = api::v1::get_v1_routes().with_state(Arc::new(RwLock::new(state)));
+
+        let (status, canonical) = get_json(
+            app.clone(),
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, alias) = get_json(
+            app,
+            "/health?service=srvA&environment=production_eu-de&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        mock.assert_async().await;
+        assert_eq!(canonical["metrics"], alias["metrics"]);
+        assert_eq!(alias["metrics"][1], json!([160, 1]));
+    }
+
+    #[tokio::test]
+    async fn test_health_disabled_service() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("GET", "/render").expect(0).create_async().await;
+        let state = get_test_state_with(server.url().as_str(), |config| {
+            config.health_metrics.get_mut("srvA").unwrap().disabled = true
+        })
+        .unwrap();
+        let app = api::v1::get_v1_routes().with_state(Arc::new(RwLock::new(state)));
+
+        let (status, _) = get_json(
+            app,
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_health_multiple_services() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]},
+                    {"target": "srvB.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let (status, body) = get_json(
+            app.clone(),
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"name": "srvA", "service_category": "compute", "environment": "env1", "metrics": [[100, 1], [160, 0]], "details": [{"ts": 100, "triggered": ["srvA.metric-1"], "values": {"srvA.metric-1": 10.0}, "matched_expression": "srvA.metric-1"}]})
+        );
+
+        let expected = json!([
+            {"name": "srvA", "service_category": "compute", "environment": "env1", "metrics": [[100, 1], [160, 0]], "details": [{"ts": 100, "triggered": ["srvA.metric-1"], "values": {"srvA.metric-1": 10.0}, "matched_expression": "srvA.metric-1"}]},
+            {"name": "srvB", "service_category": "network", "environment": "env1", "metrics": [[100, 2], [160, 0]], "details": [{"ts": 100, "triggered": ["srvB.metric-1"], "values": {"srvB.metric-1": 10.0}, "matched_expression": "srvB.metric-1"}]},
+            {"name": "srvC", "environment": "env1", "message": "Requested service not supported"}
+        ]);
+        for uri in [
+            "/health?service=srvA,srvB,srvC&environment=env1&from=-5min&to=-2min",
+            "/health?service=srvA&service=srvB&service=srvC&environment=env1&from=-5min&to=-2min",
+        ] {
+            let (status, body) = get_json(app.clone(), uri).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+            assert_eq!(body, expected, "{}", uri);
+        }
+
+        // Empty names are ignored, a single remaining service keeps the single response
+        let (status, body) = get_json(
+            app,
+            "/health?service=srvA,&environment=env1&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "srvA");
+    }
+
+    #[tokio::test]
+    async fn test_health_batch() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/health/batch")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        json!([
+                            {"service": "srvA", "environment": "env1", "from": "-5min", "to": "-2min"},
+                            {"service": "srvC", "environment": "env1", "from": "-5min", "to": "-2min", "max_data_points": 10}
+                        ])
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!([
+                {"name": "srvA", "service_category": "compute", "environment": "env1", "metrics": [[100, 1], [160, 0]], "details": [{"ts": 100, "triggered": ["srvA.metric-1"], "values": {"srvA.metric-1": 10.0}, "matched_expression": "srvA.metric-1"}]},
+                {"name": "srvC", "environment": "env1", "message": "Requested service not supported"}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_batch_max_items() {
+        let state = get_test_state("http://localhost:1");
+        state.write().await.config.server.health_batch_max_items = 1;
+        let app = api::v1::get_v1_routes().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/health/batch")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        json!([
+                            {"service": "srvA", "environment": "env1", "from": "-5min", "to": "-2min"},
+                            {"service": "srvB", "environment": "env1", "from": "-5min", "to": "-2min"}
+                        ])
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_health_timeout() {
+        // Graphite answering after a delay
+        let graphite = axum::Router::new().route(
+            "/render",
+            axum::routing::get(|| async {
+                tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+                "[]"
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(graphite.into_make_service()),
+        );
+        let app = api::v1::get_v1_routes().with_state(get_test_state(url.as_str()));
+
+        let start = std::time::Instant::now();
+        let (status, body) = get_json(
+            app,
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min&timeout_secs=1",
+        )
+        .await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body, json!({"message": "Query timed out"}));
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_rollup_health_data() {
+        use crate::api::v1::{rollup_health_data, HealthRollup};
+        use crate::types::ServiceHealthPoint;
+
+        let series: Vec<ServiceHealthPoint> = [(100, 0), (110, 2), (120, 1), (130, 0), (140, 1)]
+            .iter()
+            .map(|(ts, value)| ServiceHealthPoint {
+                ts: *ts,
+                value: *value,
+                triggered: vec![format!("metric-{}", ts)],
+                ..Default::default()
+            })
+            .collect();
+        let rollup = |mode| {
+            let res = rollup_health_data(series.clone(), mode);
+            assert_eq!(res.len(), 1);
+            (res[0].ts, res[0].value, res[0].triggered[0].clone())
+        };
+        assert_eq!(
+            rollup(HealthRollup::Max),
+            (110, 2, "metric-110".to_string())
+        );
+        assert_eq!(
+            rollup(HealthRollup::Last),
+            (140, 1, "metric-140".to_string())
+        );
+        // (0 + 2 + 1 + 0 + 1) / 5 = 0.8 => 1
+        assert_eq!(
+            rollup(HealthRollup::Avg),
+            (140, 1, "metric-140".to_string())
+        );
+
+        let series: Vec<ServiceHealthPoint> = [(100, 2), (110, 0), (120, 0)]
+            .iter()
+            .map(|(ts, value)| ServiceHealthPoint {
+                ts: *ts,
+                value: *value,
+                ..Default::default()
+            })
+            .collect();
+        // 2 / 3 => 1 is not in the series, latest point carries the value
+        let res = rollup_health_data(series, HealthRollup::Avg);
+        assert_eq!((res[0].ts, res[0].value), (120, 1));
+
+        assert!(rollup_health_data(Vec::new(), HealthRollup::Max).is_empty());
+    }
+
+    #[test]
+    fn test_downsample_health_data() {
+        use crate::api::v1::downsample_health_data;
+        use crate::types::ServiceHealthPoint;
+
+        // One hour with a datapoint every minute
+        let series: Vec<ServiceHealthPoint> = (0..60)
+            .map(|i| ServiceHealthPoint {
+                ts: 3600 + i * 60,
+                value: match i {
+                    7 => 2,
+                    25 | 28 => 1,
+                    _ => 0,
+                },
+                triggered: vec![format!("metric-{}", i)],
+                ..Default::default()
+            })
+            .collect();
+
+        let res = downsample_health_data(series.clone(), 600, 100);
+        assert_eq!(
+            res.iter().map(|x| (x.ts, x.value)).collect::<Vec<_>>(),
+            vec![
+                (3600, 2),
+                (4200, 0),
+                (4800, 1),
+                (5400, 0),
+                (6000, 0),
+                (6600, 0)
+            ]
+        );
+        // Latest worst point describes the bucket
+        assert_eq!(res[0].triggered, vec!["metric-7"]);
+        assert_eq!(res[2].triggered, vec!["metric-28"]);
+
+        // Buckets are widened to fit max_data_points
+        let res = downsample_health_data(series.clone(), 60, 4);
+        assert_eq!(
+            res.iter().map(|x| x.value).collect::<Vec<_>>(),
+            vec![2, 1, 0, 0]
+        );
+
+        // Resolution of the datapoints keeps the series
+        assert_eq!(downsample_health_data(series.clone(), 60, 100), series);
+        assert!(downsample_health_data(Vec::new(), 60, 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_health_resolution() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [
+                    [95.0, 100], [10.0, 160], [95.0, 220], [95.0, 280]
+                ]}])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let (status, body) = get_json(
+            app,
+            "/health?service=srvA&environment=env1&from=-5min&to=-2min&resolution_secs=120",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["metrics"], json!([[100, 1], [220, 0]]));
+    }
+
+    #[tokio::test]
+    async fn test_health_rollup() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        for (rollup, expected) in [
             (
-                StatusCode::CONFLICT,
-                Json(json!({"message": "Service not supported"})),
+                "max",
+                json!({"metrics": [[100, 1]], "details": [{"ts": 100, "triggered": ["srvA.metric-1"], "values": {"srvA.metric-1": 10.0}, "matched_expression": "srvA.metric-1"}]}),
+            ),
+            ("last", json!({"metrics": [[160, 0]]})),
+        ] {
+            let (status, body) = get_json(
+                app.clone(),
+                format!(
+                    "/health?service=srvA&environment=env1&from=-5min&to=-2min&rollup={}",
+                    rollup
+                )
+                .as_str(),
             )
-                .into_response()
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["metrics"], expected["metrics"], "{}", rollup);
+            assert_eq!(body.get("details"), expected.get("details"), "{}", rollup);
         }
     }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_health_request_id() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body("[]")
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health?service=srvA&environment=env1&from=-5min&to=-2min")
+                    .header("x-request-id", "req-1830")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        logs_assert(|lines: &[&str]| {
+            match lines
+                .iter()
+                .any(|x| x.contains("Fetching 1 targets from Graphite") && x.contains("req-1830"))
+            {
+                true => Ok(()),
+                false => Err("Graphite fetch event without request id".to_string()),
+            }
+        });
+    }
+
+    #[test]
+    fn test_service_health_response_version_skew() {
+        use crate::api::v1::{ServiceHealthResponse, ServiceHealthResult};
+
+        // Older convertor: no category, series without details
+        let rsp: ServiceHealthResponse = serde_json::from_value(json!({
+            "name": "srvA",
+            "environment": "env1",
+            "metrics": [[100, 2]]
+        }))
+        .unwrap();
+        assert_eq!(rsp.service_category, "");
+        assert_eq!(rsp.metrics[0].value, 2);
+        assert!(rsp.metrics[0].triggered.is_empty());
+        assert_eq!(rsp.metrics[0].matched_expression, None);
+
+        // Newer convertor: additional fields
+        let rsp: ServiceHealthResponse = serde_json::from_value(json!({
+            "name": "srvA",
+            "service_category": "compute",
+            "environment": "env1",
+            "region": "eu",
+            "metrics": [[100, 1], [160, 0]],
+            "details": [{"ts": 100, "triggered": ["srvA.metric-1"], "confidence": 0.9}]
+        }))
+        .unwrap();
+        assert_eq!(rsp.metrics[0].triggered, vec!["srvA.metric-1".to_string()]);
+        assert!(rsp.metrics[1].triggered.is_empty());
+
+        let res: Vec<ServiceHealthResult> = serde_json::from_value(json!([
+            {"name": "srvA", "environment": "env1", "metrics": []},
+            {"name": "srvC", "environment": "env1", "message": "Requested service not supported"}
+        ]))
+        .unwrap();
+        assert!(matches!(res[0], ServiceHealthResult::Ok(_)));
+        assert!(matches!(res[1], ServiceHealthResult::Err { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_flags() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "target".into(),
+                "alias(dummy1(env1.srvA.count),'srvA.metric-1')".into(),
+            ))
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [null, 130], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let (status, body) = get_json(
+            app.clone(),
+            "/flags?service=srvA&environment=env1&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"name": "srvA", "environment": "env1", "flags": [
+                {"metric": "srvA.metric-1", "datapoints": [[100, true], [160, false]]}
+            ]})
+        );
+
+        let (status, _) = get_json(
+            app,
+            "/flags?service=srvA&environment=env2&from=-5min&to=-2min",
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_explain() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Regex("from=".to_string()))
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160], [10.0, 220]]},
+                    {"target": "srvA.metric-2", "datapoints": [[90.0, 100], [null, 160], [10.0, 220]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_test_state_with(server.url().as_str(), |config| {
+            test_utils::add_test_metric_2(config);
+            config.health_metrics.get_mut("srvA").unwrap().expressions = serde_yaml::from_str(
+                "
+                - expression: '!srvA.metric-1 && !srvA.metric-2'
+                  weight: 0
+                - expression: 'srvA.metric-1'
+                  weight: 1
+                - expression: 'srvA.metric-2_value > 50'
+                  weight: 2",
+            )
+            .unwrap();
+        })
+        .unwrap();
+        let app = api::v1::get_v1_routes().with_state(Arc::new(RwLock::new(state)));
+
+        let (status, body) = get_json(
+            app.clone(),
+            "/explain?service=srvA&environment=env1&at=1970-01-01T00:02:45Z",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        mock.assert_async().await;
+        assert_eq!(body["name"], "srvA");
+        assert_eq!(body["ts"], 160);
+        assert_eq!(body["value"], 2);
+        assert_eq!(body["matched_expression"], "srvA.metric-2_value > 50");
+        let results: Vec<(&str, bool)> = body["expressions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| {
+                (
+                    x["expression"].as_str().unwrap(),
+                    x["result"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("!srvA.metric-1 && !srvA.metric-2", true),
+                ("srvA.metric-1", false),
+                ("srvA.metric-2_value > 50", true)
+            ]
+        );
+        // Metric-2 has no value at the datapoint, its previous raw value is used
+        assert_eq!(
+            body["context"],
+            json!({
+                "srvA.metric_1": false,
+                "srvA.metric_1_value": 95.0,
+                "srvA.metric_2": false,
+                "srvA.metric_2_value": 90.0
+            })
+        );
+
+        let (status, _) = get_json(app, "/explain?service=srvA&environment=env1&at=someday").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_services() {
+        let app = api::v1::get_v1_routes().with_state(get_test_state("http://localhost:1"));
+
+        let (status, body) = get_json(app, "/services").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([
+                {"name": "srvA", "category": "compute", "component_name": "Component A", "environments": ["env1"]},
+                {"name": "srvB", "category": "network", "component_name": null, "environments": ["env1"]}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_invalid_time() {
+        let app = api::v1::get_v1_routes().with_state(get_test_state("http://localhost:1"));
+
+        let (status, body) = get_json(
+            app,
+            "/health?service=srvA&environment=env1&from=someday&to=now",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            json!({"message": "Invalid time specification: someday"})
+        );
+    }
+
+    async fn post_yaml(app: axum::Router, uri: &str, body: &str) -> (StatusCode, Value) {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header("content-type", "application/yaml")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    const VALIDATE_CONFIG: &str = "
+        datasource:
+          url: 'https://a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+              - expression: 'srvA.metric-1'
+                weight: 1
+        status_dashboard:
+          url: 'https://sd'
+";
+
+    #[tokio::test]
+    async fn test_config_validate() {
+        let app = api::v1::get_v1_routes().with_state(get_test_state("http://localhost:1"));
+
+        let (status, body) = post_yaml(app, "/config/validate", VALIDATE_CONFIG).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"ok": true, "errors": []}));
+    }
+
+    #[tokio::test]
+    async fn test_config_validate_errors() {
+        let app = api::v1::get_v1_routes().with_state(get_test_state("http://localhost:1"));
+
+        let broken = VALIDATE_CONFIG
+            .replace(
+                "name: tmpl1\n            environments",
+                "name: tmpl2\n            environments",
+            )
+            .replace(
+                "expression: 'srvA.metric-1'",
+                "expression: 'srvA.metric-1 || srvA.missing'",
+            );
+        let (status, body) = post_yaml(app.clone(), "/config/validate", &broken).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({"ok": false, "errors": [
+                "flag metric srvA.metric-1 references unknown template tmpl2",
+                "health metric srvA: expression 'srvA.metric-1 || srvA.missing' references undeclared metric 'srvA.missing'"
+            ]})
+        );
+
+        let bad_op = VALIDATE_CONFIG.replace("op: lt", "op: between");
+        let (status, body) = post_yaml(app, "/config/validate", &bad_op).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], json!(false));
+        assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_live() {
+        let app = api::v1::get_v1_routes().with_state(get_test_state("http://localhost:1"));
+
+        let (status, body) = get_json(app, "/live").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"status": "ok"}));
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/metrics/find")
+            .match_query(mockito::Matcher::UrlEncoded("query".into(), "*".into()))
+            .with_status(200)
+            .with_body("[]")
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let (status, body) = get_json(app, "/ready").await;
+        mock.assert_async().await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"status": "ok"}));
+    }
+
+    #[tokio::test]
+    async fn test_ready_unreachable() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/metrics/find")
+            .match_query(mockito::Matcher::Any)
+            .with_status(502)
+            .create_async()
+            .await;
+        let app = api::v1::get_v1_routes().with_state(get_test_state(server.url().as_str()));
+
+        let (status, _) = get_json(app, "/ready").await;
+        mock.assert_async().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let app = api::v1::get_v1_routes().with_state(get_test_state("http://localhost:1"));
+        let (status, body) = get_json(app, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({"status": "unavailable", "message": "Datasource is not reachable"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_etag() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_test_state(server.url().as_str());
+        state.write().await.config.server.health_cache_max_age_secs = 30;
+        let app = api::v1::get_v1_routes().with_state(state);
+        let uri = "/health?service=srvA&environment=env1&from=-5min&to=-2min";
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "max-age=30");
+        let etag = response.headers()["etag"].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header("if-none-match", etag.clone())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], etag);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header("if-none-match", "W/\"other\"")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_cached_json_response_stable_etag() {
+        let response =
+            api::v1::cached_json_response(&axum::http::HeaderMap::new(), 30, &json!({"a": 1}));
+        // SHA-256 based, identical across restarts and instances
+        assert_eq!(
+            response.headers()["etag"],
+            "W/\"015abd7f5cc57a2dd94b7590f04ad808\""
+        );
+    }
 }
@@ -1,51 +1,248 @@
 //! Common methods
 //!
-use crate::types::{AppState, CloudMonError, CmpType, FlagMetric, ServiceHealthData};
-use chrono::DateTime;
+use crate::config::MissingMetrics;
+use crate::types::{
+    AppState, CloudMonError, CmpType, ExpressionExplanation, ExpressionMode, FlagMetric,
+    HealthExplanation, MetricExpressionDef, ServiceFlagsData, ServiceHealthData, ServiceHealthDef,
+    ServiceHealthPoint, RAW_VALUE_SUFFIX,
+};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use evalexpr::*;
-use std::collections::{BTreeMap, HashMap};
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::graphite;
 
+/// Suffix of the Graphite target fetching the baseline series of the flag metric
+pub const BASELINE_TARGET_SUFFIX: &str = ":baseline";
+
+/// Baseline values of the flag metrics: metric => ts => value
+pub type Baselines = HashMap<String, HashMap<u32, f32>>;
+
+/// Compare the raw value with the threshold
+fn compare_value(value: &Option<f32>, threshold: Option<f32>, metric: &FlagMetric) -> bool {
+    match (*value, threshold) {
+        (Some(x), Some(threshold)) => match metric.op {
+            CmpType::Lt => x < threshold,
+            CmpType::Gt => x > threshold,
+            CmpType::Eq => (x - threshold).abs() <= metric.epsilon,
+        },
+        _ => false,
+    }
+}
+
 /// Get Flag value for the metric
 pub fn get_metric_flag_state(value: &Option<f32>, metric: &FlagMetric) -> bool {
     // Convert raw value to flag
-    return match *value {
-        Some(x) => match metric.op {
-            CmpType::Lt => x < metric.threshold,
-            CmpType::Gt => x > metric.threshold,
-            CmpType::Eq => x == metric.threshold,
-        },
-        None => false,
+    compare_value(value, Some(metric.threshold), metric)
+}
+
+/// Get Flag value for the metric with the baseline value of the same timestamp. Metrics with
+/// `baseline` are compared against `baseline_factor` times the baseline value (and are not
+/// raised without it), others against the static threshold.
+pub fn get_metric_flag_state_with_baseline(
+    value: &Option<f32>,
+    baseline: Option<f32>,
+    metric: &FlagMetric,
+) -> bool {
+    match metric.baseline {
+        Some(_) => compare_value(value, baseline.map(|x| x * metric.baseline_factor), metric),
+        None => get_metric_flag_state(value, metric),
+    }
+}
+
+/// Add Graphite targets (the metric itself and its baseline) of the flag metric
+pub fn add_flag_metric_targets(
+    targets: &mut HashMap<String, String>,
+    no_alias_targets: &mut HashSet<String>,
+    name: &str,
+    metric: &FlagMetric,
+) {
+    targets.insert(name.to_string(), metric.query.clone());
+    if metric.no_alias {
+        no_alias_targets.insert(name.to_string());
+    }
+    if let Some(baseline) = &metric.baseline {
+        targets.insert(
+            format!("{}{}", name, BASELINE_TARGET_SUFFIX),
+            baseline.clone(),
+        );
+    }
+}
+
+/// Separate fetched baseline series from the flag metric series
+pub fn split_baselines(
+    raw_data: Vec<graphite::GraphiteData>,
+) -> (Vec<graphite::GraphiteData>, Baselines) {
+    let mut baselines = Baselines::new();
+    let mut data = Vec::with_capacity(raw_data.len());
+    for element in raw_data {
+        match element.target.strip_suffix(BASELINE_TARGET_SUFFIX) {
+            Some(metric) => {
+                baselines.insert(
+                    metric.to_string(),
+                    element
+                        .datapoints
+                        .iter()
+                        .filter_map(|(val, ts)| val.map(|x| (*ts, x)))
+                        .collect(),
+                );
+            }
+            None => data.push(element),
+        }
+    }
+    (data, baselines)
+}
+
+/// Baseline value of the metric at the timestamp
+pub fn get_baseline(baselines: &Baselines, metric: &str, ts: u32) -> Option<f32> {
+    baselines.get(metric).and_then(|x| x.get(&ts)).copied()
+}
+/// Parse offset as used by Graphite, i.e. `-5min`, `+1d` or `-1h30min`. Units are matched by
+/// their prefix like Graphite does (`s`, `min`, `h`, `d`, `w`, `mon`, `y` as well as `sec`,
+/// `minutes`, `hours`, `days`, `weeks`, `months`, `years`). Months have 30 and years 365 days.
+fn parse_time_offset(offset: &str) -> Option<Duration> {
+    let (sign, mut rest) = match offset.chars().next()? {
+        '-' => (-1, &offset[1..]),
+        '+' => (1, &offset[1..]),
+        _ => return None,
     };
+    if rest.is_empty() {
+        return None;
+    }
+    let mut total = Duration::zero();
+    while !rest.is_empty() {
+        let unit_start = rest.find(|c: char| !c.is_ascii_digit())?;
+        let (amount, tail) = rest.split_at(unit_start);
+        let amount: i64 = amount.parse().ok()?;
+        let unit_end = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+        let unit_secs = if unit.starts_with('s') {
+            1
+        } else if unit.starts_with("min") {
+            60
+        } else if unit.starts_with('h') {
+            3600
+        } else if unit.starts_with('d') {
+            86400
+        } else if unit.starts_with('w') {
+            7 * 86400
+        } else if unit.starts_with("mon") {
+            30 * 86400
+        } else if unit.starts_with('m') {
+            60
+        } else if unit.starts_with('y') {
+            365 * 86400
+        } else {
+            return None;
+        };
+        total += Duration::seconds(amount.checked_mul(unit_secs)?);
+        rest = tail;
+    }
+    Some(total * sign)
 }
-/// Get Service Health as described by config
-pub async fn get_service_health(
+
+/// Parse absolute time reference as used by Graphite: `now`, `today`, `yesterday`, `tomorrow`,
+/// `midnight`, `noon`, `teatime`, `%H:%M_%Y%m%d`, `%Y%m%d`, `%m/%d/%y` or `%m/%d/%Y` (UTC).
+fn parse_time_reference(reference: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).map(|x| x.and_utc());
+    let today = now.date_naive();
+    match reference {
+        "" | "now" => Some(now),
+        "today" | "midnight" => midnight(today),
+        "yesterday" => midnight(today.pred_opt()?),
+        "tomorrow" => midnight(today.succ_opt()?),
+        "noon" => midnight(today).map(|x| x + Duration::hours(12)),
+        "teatime" => midnight(today).map(|x| x + Duration::hours(16)),
+        _ => NaiveDateTime::parse_from_str(reference, "%H:%M_%Y%m%d")
+            .ok()
+            .map(|x| x.and_utc())
+            .or_else(|| {
+                ["%Y%m%d", "%m/%d/%y", "%m/%d/%Y"]
+                    .iter()
+                    .find_map(|format| NaiveDate::parse_from_str(reference, format).ok())
+                    .and_then(midnight)
+            }),
+    }
+}
+
+/// Parse time specification as used by Graphite: RFC3339 timestamp, unix timestamp, absolute
+/// reference with an optional offset (`now-5min`, `midnight+1h`, `12:00_20220101`) or relative
+/// offset like `-5min` or `-1w`.
+pub fn parse_time_spec(spec: &str) -> Result<DateTime<Utc>, CloudMonError> {
+    let spec = spec.trim();
+    let invalid = || CloudMonError::InvalidTime(spec.to_string());
+    if let Ok(ts) = DateTime::parse_from_rfc3339(spec) {
+        return Ok(ts.with_timezone(&Utc));
+    }
+    let lower = spec.to_lowercase();
+    // Plain digits are a unix timestamp unless they form a valid YYYYMMDD date
+    if !lower.is_empty() && lower.chars().all(|c| c.is_ascii_digit()) {
+        if lower.len() == 8 {
+            if let Some(date) = parse_time_reference(&lower, Utc::now()) {
+                return Ok(date);
+            }
+        }
+        let ts: i64 = lower.parse().map_err(|_| invalid())?;
+        return Utc.timestamp_opt(ts, 0).single().ok_or_else(invalid);
+    }
+    let (reference, offset) = match lower.find(['+', '-']) {
+        Some(pos) => lower.split_at(pos),
+        None => (lower.as_str(), ""),
+    };
+    if reference.is_empty() && offset.is_empty() {
+        return Err(invalid());
+    }
+    let at = parse_time_reference(reference, Utc::now()).ok_or_else(invalid)?;
+    match offset {
+        "" => Ok(at),
+        offset => Ok(at + parse_time_offset(offset).ok_or_else(invalid)?),
+    }
+}
+
+/// Fetch raw data of all flag metrics used by the service health in the environment
+async fn get_service_raw_data(
     state: &AppState,
     service: &str,
     environment: &str,
     from: &str,
     to: &str,
     max_data_points: u16,
-) -> Result<ServiceHealthData, CloudMonError> {
-    if !state.health_metrics.contains_key(service) {
-        return Err(CloudMonError::ServiceNotSupported);
-    }
-    let hm_config = state.health_metrics.get(service).unwrap();
-    let metric_names: Vec<String> = Vec::from(hm_config.metrics.clone());
+    request_id: Option<&str>,
+) -> Result<Vec<graphite::GraphiteData>, CloudMonError> {
+    let hm_config = state
+        .health_metrics
+        .get(service)
+        .ok_or(CloudMonError::ServiceNotSupported)?;
+    let metric_names: Vec<String> = hm_config.metrics.clone();
 
     tracing::trace!("Requesting metrics {:?}", metric_names);
     let mut graphite_targets: HashMap<String, String> = HashMap::new();
+    let mut no_alias_targets: HashSet<String> = HashSet::new();
     // Construct target=>query map
     for metric_name in metric_names.iter() {
         if let Some(metric) = state.flag_metrics.get(metric_name) {
             match metric.get(environment) {
                 Some(m) => {
-                    graphite_targets.insert(metric_name.clone(), m.query.clone());
+                    add_flag_metric_targets(
+                        &mut graphite_targets,
+                        &mut no_alias_targets,
+                        metric_name,
+                        m,
+                    );
+                }
+                _ if hm_config.missing_metrics == MissingMetrics::Lenient => {
+                    tracing::warn!(
+                        "Metric {} is not available for env {}, treating it as not raised",
+                        metric_name,
+                        environment
+                    );
                 }
                 _ => {
                     tracing::debug!(
-                        "Can not find metric {} for env {}",
+                        "Can not find metric {} for env {}",
                         metric_name,
                         environment
                     );
@@ -55,24 +252,185 @@ pub async fn get_service_health(
         }
     }
     tracing::debug!("Requesting Graphite {:?}", graphite_targets);
-    let raw_data: Vec<graphite::GraphiteData> = graphite::get_graphite_data(
-        &state.req_client,
-        &state.config.datasource.url.as_str(),
+    graphite::get_graphite_data(
+        &graphite::FetchContext::from_state(state),
         &graphite_targets,
-        DateTime::parse_from_rfc3339(from).ok(),
-        Some(from.to_string()),
-        DateTime::parse_from_rfc3339(to).ok(),
-        Some(to.to_string()),
+        &graphite::TimeRange::parse(from, to),
         max_data_points,
+        &graphite::QueryOptions::from(&state.config.datasource)
+            .with_request_id(request_id)
+            .with_no_alias_targets(no_alias_targets),
     )
     .await
-    .unwrap();
+}
 
+/// Get states of the flag metrics used by the service health
+pub async fn get_service_flags(
+    state: &AppState,
+    service: &str,
+    environment: &str,
+    from: &str,
+    to: &str,
+    max_data_points: u16,
+    request_id: Option<&str>,
+) -> Result<ServiceFlagsData, CloudMonError> {
+    let raw_data = get_service_raw_data(
+        state,
+        service,
+        environment,
+        from,
+        to,
+        max_data_points,
+        request_id,
+    )
+    .await?;
     tracing::trace!("Response from Graphite {:?}", raw_data);
+    let (raw_data, baselines) = split_baselines(raw_data);
 
-    let mut result: ServiceHealthData = Vec::new();
+    let mut result: ServiceFlagsData = BTreeMap::new();
+    for data_element in raw_data.iter() {
+        match state
+            .flag_metrics
+            .get(&data_element.target)
+            .and_then(|x| x.get(environment))
+        {
+            Some(metric) => {
+                result.insert(
+                    data_element.target.clone(),
+                    data_element
+                        .datapoints
+                        .iter()
+                        .filter(|(val, _)| val.is_some())
+                        .map(|(val, ts)| {
+                            let baseline = get_baseline(&baselines, &data_element.target, *ts);
+                            (
+                                *ts,
+                                get_metric_flag_state_with_baseline(val, baseline, metric),
+                            )
+                        })
+                        .collect(),
+                );
+            }
+            None => {
+                tracing::warn!(
+                    "DB Response contains unknown target: {}",
+                    data_element.target
+                );
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Result of querying the flag metric in the environment
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricVerification {
+    pub metric: String,
+    pub environment: String,
+    /// Whether the query returned any non-null datapoint
+    pub has_data: bool,
+}
+
+/// Maximal number of flag metric queries run in parallel during the verification
+const VERIFY_CONCURRENCY: usize = 8;
+
+/// Query every flag metric in every environment for a short recent window to find broken
+/// queries (wrong paths or functions) returning no data. Queries run with bounded concurrency
+/// and the whole verification is limited by `timeout`. Failed queries and queries not finished
+/// in time count as without data.
+pub async fn verify_flag_metrics(
+    state: &AppState,
+    from: &str,
+    timeout: std::time::Duration,
+) -> Vec<MetricVerification> {
+    let mut checks: Vec<(&String, &String, &FlagMetric)> = state
+        .flag_metrics
+        .iter()
+        .flat_map(|(metric_name, environments)| {
+            environments
+                .iter()
+                .filter(|(env, _)| !state.environment_aliases.contains_key(*env))
+                .map(move |(environment, metric)| (metric_name, environment, metric))
+        })
+        .collect();
+    checks.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    let mut result: Vec<MetricVerification> = checks
+        .iter()
+        .map(|(metric_name, environment, _)| MetricVerification {
+            metric: (*metric_name).clone(),
+            environment: (*environment).clone(),
+            has_data: false,
+        })
+        .collect();
+
+    let mut queries = stream::iter(checks.iter().enumerate())
+        .map(|(idx, (metric_name, environment, metric))| async move {
+            let targets = HashMap::from([((*metric_name).clone(), metric.query.clone())]);
+            let mut no_alias_targets: HashSet<String> = HashSet::new();
+            if metric.no_alias {
+                no_alias_targets.insert((*metric_name).clone());
+            }
+            let has_data = match graphite::get_graphite_data(
+                &graphite::FetchContext::from_state(state).with_cache(None),
+                &targets,
+                &graphite::TimeRange {
+                    from: Some(graphite::TimeBound::Raw(from.to_string())),
+                    until: Some(graphite::TimeBound::Raw("now".to_string())),
+                },
+                1,
+                &graphite::QueryOptions::from(&state.config.datasource)
+                    .with_no_alias_targets(no_alias_targets),
+            )
+            .await
+            {
+                Ok(data) => data
+                    .iter()
+                    .any(|x| x.datapoints.iter().any(|(val, _)| val.is_some())),
+                Err(e) => {
+                    tracing::warn!(
+                        "Query of metric {} in {} failed: {}",
+                        metric_name,
+                        environment,
+                        e
+                    );
+                    false
+                }
+            };
+            (idx, has_data)
+        })
+        .buffer_unordered(VERIFY_CONCURRENCY);
+    let mut verified: usize = 0;
+    let finished = tokio::time::timeout(timeout, async {
+        while let Some((idx, has_data)) = queries.next().await {
+            result[idx].has_data = has_data;
+            verified += 1;
+        }
+    })
+    .await;
+    if finished.is_err() {
+        tracing::warn!(
+            "Metric verification timed out, {} of {} flag metrics not verified",
+            result.len() - verified,
+            result.len()
+        );
+    }
+    result
+}
+
+/// Flag state and raw value of the metrics by timestamp: ts => metric => (flag, raw value)
+type MetricsMap = BTreeMap<u32, HashMap<String, (bool, f32)>>;
+
+/// Reorganize the fetched series of the service flag metrics by timestamp for health evaluation
+fn build_metrics_map(
+    state: &AppState,
+    service: &str,
+    environment: &str,
+    raw_data: Vec<graphite::GraphiteData>,
+) -> MetricsMap {
+    let (raw_data, baselines) = split_baselines(raw_data);
     // Iterate over all data elements and reorg them for health evaluation
-    let mut metrics_map: BTreeMap<u32, HashMap<String, bool>> = BTreeMap::new();
+    // ts => metric => (flag, raw value)
+    let mut metrics_map: MetricsMap = BTreeMap::new();
     for data_element in raw_data.iter() {
         // target + datapoints
         tracing::trace!("Processing dataframe {:?}", data_element);
@@ -82,13 +440,29 @@ pub async fn get_service_health(
                 tracing::trace!("Processing datapoints for metric {:?}", metric_cfg);
                 let metric = metric_cfg.get(environment).unwrap();
                 // Iterate over all fetched series
+                let mut prev_flag: Option<bool> = None;
                 for (val, ts) in data_element.datapoints.iter() {
                     // Convert raw value to flag
-                    if let Some(_) = val {
-                        metrics_map.entry(*ts).or_insert(HashMap::new()).insert(
-                            data_element.target.clone(),
-                            get_metric_flag_state(val, metric),
-                        );
+                    if let Some(raw) = val {
+                        let baseline = get_baseline(&baselines, &data_element.target, *ts);
+                        let flag = get_metric_flag_state_with_baseline(val, baseline, metric);
+                        if state.config.log_flag_transitions && flag && prev_flag == Some(false) {
+                            tracing::info!(
+                                service,
+                                environment,
+                                metric = data_element.target.as_str(),
+                                threshold = metric.threshold,
+                                op = ?metric.op,
+                                value = *raw,
+                                ts = *ts,
+                                "Flag metric crossed threshold"
+                            );
+                        }
+                        prev_flag = Some(flag);
+                        metrics_map
+                            .entry(*ts)
+                            .or_default()
+                            .insert(data_element.target.clone(), (flag, *raw));
                     }
                 }
             }
@@ -100,56 +474,1164 @@ pub async fn get_service_health(
             }
         }
     }
-    tracing::trace!("Metric map = {:?}", metrics_map);
+    metrics_map
+}
 
-    // Loop through data map and evaluate health
-    let hm_config = state.health_metrics.get(service).unwrap();
-    for (ts, ts_val) in metrics_map.iter() {
-        let mut context = HashMapContext::new();
-        // build context with all metrics
-        for metric in hm_config.metrics.iter() {
-            let xval = match ts_val.get(metric) {
-                Some(&x) => x,
-                _ => false,
-            };
-            context
-                .set_value(metric.replace("-", "_").into(), Value::from(xval))
-                .unwrap();
-        }
-        let mut expression_res: u8 = 0;
-        // loop over all expressions
-        for expr in hm_config.expressions.iter() {
-            // if expression weight is lower then what we have already - skip
-            if expr.weight as u8 <= expression_res {
-                continue;
+/// Build the expression context of the datapoint together with the (not yet evaluated) point.
+/// `raw_values` holds the latest known raw value of every metric and is updated with the values
+/// of the datapoint.
+fn build_health_context<'a>(
+    hm_config: &'a ServiceHealthDef,
+    ts: u32,
+    ts_val: &HashMap<String, (bool, f32)>,
+    raw_values: &mut HashMap<&'a str, f32>,
+) -> (HashMapContext, ServiceHealthPoint) {
+    let mut context = HashMapContext::new();
+    let mut point = ServiceHealthPoint {
+        ts,
+        ..Default::default()
+    };
+    for metric in hm_config.metrics.iter() {
+        let xval = match ts_val.get(metric) {
+            Some(&(x, raw)) => {
+                if x {
+                    point.triggered.push(metric.clone());
+                    point.values.insert(metric.clone(), raw as f64);
+                }
+                raw_values.insert(metric.as_str(), raw);
+                x
             }
-            match eval_boolean_with_context(expr.expression.as_str(), &context) {
-                Ok(m) => {
-                    if m {
-                        expression_res = expr.weight as u8;
+            _ => false,
+        };
+        let var_name = metric.replace("-", "_");
+        // Metrics without any value yet compare false against any number
+        let raw = raw_values.get(metric.as_str()).copied().unwrap_or(f32::NAN);
+        context
+            .set_value(
+                format!("{}{}", var_name, RAW_VALUE_SUFFIX),
+                Value::from(raw as f64),
+            )
+            .unwrap();
+        context.set_value(var_name, Value::from(xval)).unwrap();
+    }
+    (context, point)
+}
+
+/// Evaluate the health expressions in the context setting value and matched expression of the
+/// point
+fn evaluate_health_point(
+    service: &str,
+    hm_config: &ServiceHealthDef,
+    context: &HashMapContext,
+    point: &mut ServiceHealthPoint,
+) -> Result<(), CloudMonError> {
+    let mut expression_res: u8 = 0;
+    // loop over all expressions
+    for expr in hm_config.expressions.iter() {
+        let res = match expr.mode {
+            ExpressionMode::Boolean if expr.weight_or_default() == 0 => {
+                // "known healthy" expression only names the result while nothing else
+                // matched
+                if expression_res > 0 || point.matched_expression.is_some() {
+                    continue;
+                }
+                let matched = match &expr.node {
+                    Some(node) => node.eval_boolean_with_context(context),
+                    None => eval_boolean_with_context(expr.expression.as_str(), context),
+                };
+                match matched {
+                    Ok(true) => {
+                        point.matched_expression = Some(expr.configured_expression().to_string())
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
                         tracing::debug!(
-                            "Summary of evaluation expression for service: {:?}, expression: {:?}, weight: {:?}",
-                            service,
+                            "Error during evaluation of {:?} [context: {:?}]: {:?}",
                             expr.expression,
-                            expr.weight
+                            context,
+                            e
                         );
+                        return Err(CloudMonError::ExpressionError);
                     }
                 }
-                Err(e) => {
+                continue;
+            }
+            ExpressionMode::Boolean => {
+                // if expression weight is lower then what we have already - skip
+                if expr.weight_or_default() as u8 <= expression_res {
+                    continue;
+                }
+                match &expr.node {
+                    Some(node) => node.eval_boolean_with_context(context),
+                    None => eval_boolean_with_context(expr.expression.as_str(), context),
+                }
+                .map(|m| if m { expr.weight_or_default() as u8 } else { 0 })
+            }
+            ExpressionMode::Numeric => match &expr.node {
+                Some(node) => node.eval_number_with_context(context),
+                None => eval_number_with_context(expr.expression.as_str(), context),
+            }
+            .map(|x| x.round().clamp(0.0, u8::MAX as f64) as u8),
+        };
+        match res {
+            Ok(weight) => {
+                if weight > expression_res {
+                    expression_res = weight;
+                    point.matched_expression = Some(expr.configured_expression().to_string());
                     tracing::debug!(
-                        "Error during evaluation of {:?} [context: {:?}]: {:?}",
+                        "Summary of evaluation expression for service: {:?}, expression: {:?}, weight: {:?}",
+                        service,
                         expr.expression,
-                        context,
-                        e
+                        weight
                     );
-                    return Err(CloudMonError::ExpressionError);
                 }
             }
+            Err(e) => {
+                tracing::debug!(
+                    "Error during evaluation of {:?} [context: {:?}]: {:?}",
+                    expr.expression,
+                    context,
+                    e
+                );
+                return Err(CloudMonError::ExpressionError);
+            }
+        }
+    }
+    point.value = expression_res;
+    Ok(())
+}
+
+/// Get Service Health as described by config
+#[tracing::instrument(
+    name = "service_health",
+    skip_all,
+    fields(service = %service, environment = %environment, request_id = ?request_id)
+)]
+pub async fn get_service_health(
+    state: &AppState,
+    service: &str,
+    environment: &str,
+    from: &str,
+    to: &str,
+    max_data_points: u16,
+    request_id: Option<&str>,
+) -> Result<ServiceHealthData, CloudMonError> {
+    let raw_data = get_service_raw_data(
+        state,
+        service,
+        environment,
+        from,
+        to,
+        max_data_points,
+        request_id,
+    )
+    .await?;
+
+    tracing::trace!("Response from Graphite {:?}", raw_data);
+    let metrics_map = build_metrics_map(state, service, environment, raw_data);
+    tracing::trace!("Metric map = {:?}", metrics_map);
+
+    // Loop through data map and evaluate health
+    let hm_config = state.health_metrics.get(service).unwrap();
+    // Latest known raw value of every metric
+    let mut raw_values: HashMap<&str, f32> = HashMap::new();
+    let mut result: ServiceHealthData = Vec::new();
+    for (ts, ts_val) in metrics_map.iter() {
+        let (context, mut point) = build_health_context(hm_config, *ts, ts_val, &mut raw_values);
+        evaluate_health_point(service, hm_config, &context, &mut point)?;
+        result.push(point);
+    }
+
+    if result.len() < hm_config.min_datapoints {
+        tracing::info!(
+            service,
+            environment,
+            datapoints = result.len(),
+            min_datapoints = hm_config.min_datapoints,
+            "Too few datapoints in the window, not evaluating health"
+        );
+        for point in result.iter_mut() {
+            *point = ServiceHealthPoint {
+                ts: point.ts,
+                ..Default::default()
+            };
         }
-        result.push((*ts, expression_res));
     }
 
     tracing::debug!("Summary data: {:?}, length={}", result, result.len());
 
-    return Ok(result);
+    Ok(result)
+}
+
+/// Seconds of data fetched before the explained time, raw values of earlier datapoints are
+/// used by the expressions
+const EXPLAIN_WINDOW_BEFORE_SECS: i64 = 600;
+/// Seconds of data fetched after the explained time
+const EXPLAIN_WINDOW_AFTER_SECS: i64 = 60;
+
+/// Evaluate every expression of the service health separately
+fn explain_expression(
+    expr: &MetricExpressionDef,
+    context: &HashMapContext,
+) -> Result<ExpressionExplanation, CloudMonError> {
+    let (result, value) = match expr.mode {
+        ExpressionMode::Boolean => match &expr.node {
+            Some(node) => node.eval_boolean_with_context(context),
+            None => eval_boolean_with_context(expr.expression.as_str(), context),
+        }
+        .map(|x| (x, None)),
+        ExpressionMode::Numeric => match &expr.node {
+            Some(node) => node.eval_number_with_context(context),
+            None => eval_number_with_context(expr.expression.as_str(), context),
+        }
+        .map(|x| (x.round() > 0.0, Some(x))),
+    }
+    .map_err(|e| {
+        tracing::debug!(
+            "Error during evaluation of {:?} [context: {:?}]: {:?}",
+            expr.expression,
+            context,
+            e
+        );
+        CloudMonError::ExpressionError
+    })?;
+    Ok(ExpressionExplanation {
+        expression: expr.configured_expression().to_string(),
+        weight: expr.weight_or_default(),
+        mode: expr.mode.clone(),
+        result,
+        value,
+    })
+}
+
+/// Explain the health of the service at the datapoint closest to `at`: the result of every
+/// expression and the context they are evaluated in. The `min_datapoints` gate is not applied.
+pub async fn explain_service_health(
+    state: &AppState,
+    service: &str,
+    environment: &str,
+    at: DateTime<Utc>,
+    request_id: Option<&str>,
+) -> Result<HealthExplanation, CloudMonError> {
+    let hm_config = state
+        .health_metrics
+        .get(service)
+        .ok_or(CloudMonError::ServiceNotSupported)?;
+    let from = (at - Duration::seconds(EXPLAIN_WINDOW_BEFORE_SECS)).to_rfc3339();
+    let to = (at + Duration::seconds(EXPLAIN_WINDOW_AFTER_SECS)).to_rfc3339();
+    let raw_data = get_service_raw_data(
+        state,
+        service,
+        environment,
+        from.as_str(),
+        to.as_str(),
+        u16::MAX,
+        request_id,
+    )
+    .await?;
+    let metrics_map = build_metrics_map(state, service, environment, raw_data);
+    let at_ts = at.timestamp();
+    let Some(ts) = metrics_map
+        .keys()
+        .min_by_key(|ts| (**ts as i64 - at_ts).abs())
+        .copied()
+    else {
+        return Ok(HealthExplanation::default());
+    };
+
+    // Earlier datapoints only provide the latest raw values
+    let mut raw_values: HashMap<&str, f32> = HashMap::new();
+    for (ts, ts_val) in metrics_map.range(..ts) {
+        build_health_context(hm_config, *ts, ts_val, &mut raw_values);
+    }
+    let (context, mut point) =
+        build_health_context(hm_config, ts, &metrics_map[&ts], &mut raw_values);
+    evaluate_health_point(service, hm_config, &context, &mut point)?;
+    let expressions = hm_config
+        .expressions
+        .iter()
+        .map(|expr| explain_expression(expr, &context))
+        .collect::<Result<Vec<_>, _>>()?;
+    let context = context
+        .iter_variables()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Boolean(x) => serde_json::Value::from(x),
+                // NaN (no value yet) is not representable in JSON
+                Value::Float(x) => serde_json::Number::from_f64(x)
+                    .map(serde_json::Value::Number)
+                    .unwrap_or_default(),
+                other => serde_json::Value::from(other.to_string()),
+            };
+            (name, value)
+        })
+        .collect();
+    Ok(HealthExplanation {
+        ts: Some(ts),
+        value: point.value,
+        matched_expression: point.matched_expression,
+        expressions,
+        context,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use serde_json::json;
+
+    /// Prepare state for srvA with metric-1 (lt 90) and metric-2 (gt 80) flags and given health
+    /// expressions (YAML list)
+    fn get_expressions_test_state(url: &str, expressions: &str) -> types::AppState {
+        test_utils::get_test_state_with(url, |config| {
+            test_utils::add_test_metric_2(config);
+            config.health_metrics.get_mut("srvA").unwrap().expressions =
+                serde_yaml::from_str(expressions).unwrap();
+        })
+        .unwrap()
+    }
+
+    const EXPR_SIMPLE: &str = "
+              - expression: 'srvA.metric-1'
+                weight: 1";
+
+    #[tokio::test]
+    async fn test_get_service_health_graphite_error() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+
+        let res =
+            common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None).await;
+        assert!(matches!(
+            res,
+            Err(types::CloudMonError::GraphiteError { .. })
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_values() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].value, 1);
+        assert_eq!(res[0].triggered, vec!["srvA.metric-1".to_string()]);
+        assert_eq!(res[0].values.get("srvA.metric-1"), Some(&10.0));
+        assert_eq!(res[1].value, 0);
+        assert!(res[1].triggered.is_empty());
+        assert!(res[1].values.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_baseline() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Regex(
+                "target=alias%28timeShift%28dummy1%28env1.srvA.count%29".to_string(),
+            ))
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [
+                        [10.0, 100], [30.0, 160], [50.0, 220], [40.0, 280]
+                    ]},
+                    {"target": "srvA.metric-1:baseline", "datapoints": [
+                        [10.0, 100], [10.0, 160], [30.0, 220], [null, 280]
+                    ]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let f = format!(
+            "
+        datasource:
+          url: '{}'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            baseline: timeShift(dummy1($environment.$service.count), '7d')
+            baseline_factor: 2
+            op: gt
+            threshold: 0
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+{}
+",
+            server.url(),
+            EXPR_SIMPLE
+        );
+        let mut state = types::AppState::new(config::Config::from_config_str(&f).unwrap());
+        state.process_config().unwrap();
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        // Raised only where the value exceeds twice the baseline, never without baseline
+        assert_eq!(
+            res.iter().map(|x| (x.ts, x.value)).collect::<Vec<_>>(),
+            vec![(100, 0), (160, 1), (220, 0), (280, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_min_datapoints() {
+        let mut server = mockito::Server::new_async().await;
+        let mut state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+        state.health_metrics.get_mut("srvA").unwrap().min_datapoints = 3;
+
+        // Single bad point is suppressed
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[10.0, 100]]}]).to_string(),
+            )
+            .create_async()
+            .await;
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].ts, 100);
+        assert_eq!(res[0].value, 0);
+        assert!(res[0].triggered.is_empty());
+        mock.remove_async().await;
+
+        // Enough points are evaluated
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [
+                    [95.0, 100], [95.0, 160], [10.0, 220]
+                ]}])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-3min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|x| x.value).collect::<Vec<_>>(),
+            vec![0, 0, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_flag_metrics() {
+        let mut server = mockito::Server::new_async().await;
+        let _with_data = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Regex("target=alias%28dummy1".to_string()))
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[null, 100], [1.0, 160]]}])
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let _empty = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Regex("target=alias%28dummy2".to_string()))
+            .with_body("[]")
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+
+        let res =
+            common::verify_flag_metrics(&state, "-5min", std::time::Duration::from_secs(5)).await;
+        assert_eq!(
+            res.iter()
+                .map(|x| (x.metric.as_str(), x.environment.as_str(), x.has_data))
+                .collect::<Vec<_>>(),
+            vec![
+                ("srvA.metric-1", "env1", true),
+                ("srvA.metric-2", "env1", false)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_flag_metrics_timeout() {
+        // Graphite answering after a delay
+        let graphite = axum::Router::new().route(
+            "/render",
+            axum::routing::get(|| async {
+                tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+                "[]"
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(graphite.into_make_service()),
+        );
+        let state = get_expressions_test_state(url.as_str(), EXPR_SIMPLE);
+
+        let start = std::time::Instant::now();
+        let res =
+            common::verify_flag_metrics(&state, "-5min", std::time::Duration::from_secs(1)).await;
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|x| !x.has_data));
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_matched_expression() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [10.0, 160], [95.0, 220]]},
+                    {"target": "srvA.metric-2", "datapoints": [[95.0, 100], [10.0, 160], [10.0, 220]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'srvA.metric-1'
+                weight: 1
+              - expression: 'srvA.metric-1 && srvA.metric-2'
+                weight: 2",
+        );
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 3);
+        // Both expressions match - highest weight wins
+        assert_eq!(res[0].value, 2);
+        assert_eq!(
+            res[0].matched_expression.as_deref(),
+            Some("srvA.metric-1 && srvA.metric-2")
+        );
+        assert_eq!(res[1].value, 1);
+        assert_eq!(res[1].matched_expression.as_deref(), Some("srvA.metric-1"));
+        assert_eq!(res[2].value, 0);
+        assert_eq!(res[2].matched_expression, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_known_healthy() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]},
+                    {"target": "srvA.metric-2", "datapoints": [[10.0, 100], [10.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: '!srvA.metric-1 && !srvA.metric-2'
+                weight: 0
+              - expression: 'srvA.metric-1'
+                weight: 1",
+        );
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        // Raised metric is reported with its expression
+        assert_eq!(res[0].value, 1);
+        assert_eq!(res[0].matched_expression.as_deref(), Some("srvA.metric-1"));
+        // All clear expression names the healthy result
+        assert_eq!(res[1].value, 0);
+        assert_eq!(
+            res[1].matched_expression.as_deref(),
+            Some("!srvA.metric-1 && !srvA.metric-2")
+        );
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_service_health_flag_transitions() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [
+                        [10.0, 100], [95.0, 160], [10.0, 220], [10.0, 280], [95.0, 340], [20.0, 400]
+                    ]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let mut state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+
+        // Disabled by default
+        common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert!(!logs_contain("Flag metric crossed threshold"));
+
+        state.config.log_flag_transitions = true;
+        common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        logs_assert(|lines: &[&str]| {
+            let events: Vec<&&str> = lines
+                .iter()
+                .filter(|x| x.contains("Flag metric crossed threshold"))
+                .collect();
+            if events.len() != 2 {
+                return Err(format!("expected 2 transitions, got {}", events.len()));
+            }
+            for (event, (value, ts)) in events.iter().zip([(10, 220), (20, 400)]) {
+                for field in [
+                    "service=\"srvA\"".to_string(),
+                    "environment=\"env1\"".to_string(),
+                    "metric=\"srvA.metric-1\"".to_string(),
+                    "threshold=90".to_string(),
+                    "op=Lt".to_string(),
+                    format!("value={}", value),
+                    format!("ts={}", ts),
+                ] {
+                    if !event.contains(field.as_str()) {
+                        return Err(format!("{} missing in {}", field, event));
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_service_health_span_fields() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(json!([]).to_string())
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+
+        common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, Some("req-1"))
+            .await
+            .unwrap();
+        assert!(logs_contain(
+            "service_health{service=srvA environment=env1 request_id=Some(\"req-1\")}"
+        ));
+    }
+
+    #[test]
+    fn test_get_metric_flag_state_eq_epsilon() {
+        let exact = types::FlagMetric {
+            op: types::CmpType::Eq,
+            threshold: 100.0,
+            ..Default::default()
+        };
+        assert!(common::get_metric_flag_state(&Some(100.0), &exact));
+        assert!(!common::get_metric_flag_state(&Some(99.999), &exact));
+        assert!(!common::get_metric_flag_state(&None, &exact));
+
+        let tolerant = types::FlagMetric {
+            epsilon: 0.01,
+            ..exact
+        };
+        assert!(common::get_metric_flag_state(&Some(100.0), &tolerant));
+        assert!(common::get_metric_flag_state(&Some(99.999), &tolerant));
+        assert!(common::get_metric_flag_state(&Some(100.005), &tolerant));
+        assert!(!common::get_metric_flag_state(&Some(99.9), &tolerant));
+    }
+
+    #[test]
+    fn test_parse_time_spec() {
+        let now = chrono::Utc::now();
+        let near = |x: chrono::DateTime<chrono::Utc>, expected: chrono::DateTime<chrono::Utc>| {
+            (x - expected).num_seconds().abs() <= 1
+        };
+        assert!(near(common::parse_time_spec("now").unwrap(), now));
+        assert!(near(
+            common::parse_time_spec("-30s").unwrap(),
+            now - chrono::Duration::seconds(30)
+        ));
+        assert!(near(
+            common::parse_time_spec("-5min").unwrap(),
+            now - chrono::Duration::minutes(5)
+        ));
+        assert!(near(
+            common::parse_time_spec("-1h").unwrap(),
+            now - chrono::Duration::hours(1)
+        ));
+        assert!(near(
+            common::parse_time_spec("-2d").unwrap(),
+            now - chrono::Duration::days(2)
+        ));
+        assert_eq!(
+            common::parse_time_spec("2022-01-01T01:00:00+01:00")
+                .unwrap()
+                .to_rfc3339(),
+            "2022-01-01T00:00:00+00:00"
+        );
+        for (spec, offset) in [
+            ("-1w", chrono::Duration::weeks(1)),
+            ("-7days", chrono::Duration::days(7)),
+            ("-24hours", chrono::Duration::hours(24)),
+            ("-1mon", chrono::Duration::days(30)),
+            ("-2months", chrono::Duration::days(60)),
+            ("-1y", chrono::Duration::days(365)),
+            ("-5years", chrono::Duration::days(5 * 365)),
+            ("-10m", chrono::Duration::minutes(10)),
+            ("-90sec", chrono::Duration::seconds(90)),
+            ("-1h30min", chrono::Duration::minutes(90)),
+            ("now-5min", chrono::Duration::minutes(5)),
+            ("NOW-1d", chrono::Duration::days(1)),
+            ("+1h", -chrono::Duration::hours(1)),
+        ] {
+            assert!(
+                near(common::parse_time_spec(spec).unwrap(), now - offset),
+                "{}",
+                spec
+            );
+        }
+        let midnight = now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+        for (spec, expected) in [
+            ("today", midnight),
+            ("midnight", midnight),
+            ("yesterday", midnight - chrono::Duration::days(1)),
+            ("tomorrow", midnight + chrono::Duration::days(1)),
+            ("noon", midnight + chrono::Duration::hours(12)),
+            ("midnight+2h", midnight + chrono::Duration::hours(2)),
+            ("yesterday-1d", midnight - chrono::Duration::days(2)),
+        ] {
+            assert_eq!(common::parse_time_spec(spec).unwrap(), expected, "{}", spec);
+        }
+        for (spec, expected) in [
+            ("14:30_20220101", "2022-01-01T14:30:00+00:00"),
+            ("20220101", "2022-01-01T00:00:00+00:00"),
+            ("20220101+1h", "2022-01-01T01:00:00+00:00"),
+            ("01/02/22", "2022-01-02T00:00:00+00:00"),
+            ("01/02/2022", "2022-01-02T00:00:00+00:00"),
+            ("1640995200", "2022-01-01T00:00:00+00:00"),
+        ] {
+            assert_eq!(
+                common::parse_time_spec(spec).unwrap().to_rfc3339(),
+                expected,
+                "{}",
+                spec
+            );
+        }
+        for spec in [
+            "",
+            "-",
+            "-5",
+            "min",
+            "-5parsecs",
+            "-x5min",
+            "5min",
+            "now-",
+            "someday",
+            "25:00_20220101",
+        ] {
+            assert!(
+                matches!(
+                    common::parse_time_spec(spec),
+                    Err(types::CloudMonError::InvalidTime(_))
+                ),
+                "{}",
+                spec
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_no_alias() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("target=alias%28dummy1%28env1.srvA.count%29".into()),
+                mockito::Matcher::Regex("target=dummy2%28env1.srvA.count%29(&|$)".into()),
+            ]))
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]},
+                    {"target": "series named by the query", "datapoints": [[95.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let mut state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'srvA.metric-1 && srvA.metric-2'
+                weight: 2",
+        );
+        state
+            .flag_metrics
+            .get_mut("srvA.metric-2")
+            .unwrap()
+            .get_mut("env1")
+            .unwrap()
+            .no_alias = true;
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(res[0].value, 2);
+        assert_eq!(
+            res[0].triggered,
+            vec!["srvA.metric-1".to_string(), "srvA.metric-2".to_string()]
+        );
+        assert_eq!(res[1].value, 0);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_service_health_no_alias_count_mismatch() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "series named by the query", "datapoints": [[10.0, 100]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let mut state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+        for metric in ["srvA.metric-1", "srvA.metric-2"] {
+            state
+                .flag_metrics
+                .get_mut(metric)
+                .unwrap()
+                .get_mut("env1")
+                .unwrap()
+                .no_alias = true;
+        }
+
+        // One series for two not aliased targets can not be attributed by position
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+        assert!(logs_contain(
+            "Cannot attribute 1 unknown series to 2 not aliased targets by position"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_positional_fallback() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "renamed(srvA.metric-1)", "datapoints": [[10.0, 100]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let mut state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+        state.health_metrics.get_mut("srvA").unwrap().metrics = vec!["srvA.metric-1".to_string()];
+
+        // Unknown series is dropped by default
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert!(res.is_empty());
+
+        state.config.datasource.positional_fallback = true;
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].value, 1);
+        assert_eq!(res[0].triggered, vec!["srvA.metric-1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_missing_metric_strict() {
+        let server = mockito::Server::new_async().await;
+        let mut state = get_expressions_test_state(server.url().as_str(), EXPR_SIMPLE);
+        state
+            .flag_metrics
+            .get_mut("srvA.metric-2")
+            .unwrap()
+            .remove("env1");
+
+        let res =
+            common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None).await;
+        assert!(matches!(res, Err(types::CloudMonError::EnvNotSupported)));
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_missing_metric_lenient() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "target".into(),
+                "alias(dummy1(env1.srvA.count),'srvA.metric-1')".into(),
+            ))
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [95.0, 160]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let mut state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'srvA.metric-1 && !srvA.metric-2'
+                weight: 1
+              - expression: 'srvA.metric-2'
+                weight: 2",
+        );
+        state
+            .flag_metrics
+            .get_mut("srvA.metric-2")
+            .unwrap()
+            .remove("env1");
+        state
+            .health_metrics
+            .get_mut("srvA")
+            .unwrap()
+            .missing_metrics = config::MissingMetrics::Lenient;
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].value, 1);
+        assert_eq!(res[1].value, 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_numeric_expression() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[95.0, 100], [10.0, 160], [10.0, 220]]},
+                    {"target": "srvA.metric-2", "datapoints": [[10.0, 100], [10.0, 160], [95.0, 220]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'if(srvA.metric-1, 1, 0) + if(srvA.metric-2, 2, 0)'
+                mode: numeric",
+        );
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|x| x.value).collect::<Vec<u8>>(),
+            vec![0, 1, 3]
+        );
+        assert_eq!(res[0].matched_expression, None);
+        assert_eq!(
+            res[2].matched_expression,
+            Some("if(srvA.metric-1, 1, 0) + if(srvA.metric-2, 2, 0)".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_raw_values() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [50.0, 160], [50.0, 220]]},
+                    {"target": "srvA.metric-2", "datapoints": [[95.0, 100], [95.0, 160], [null, 220]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'srvA.metric-1 && srvA.metric-2_value > 90'
+                weight: 2",
+        );
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        // metric-1 is raised everywhere, the last known metric-2 value is used at 220
+        assert_eq!(
+            res.iter().map(|x| x.value).collect::<Vec<u8>>(),
+            vec![2, 2, 2]
+        );
+
+        let state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'srvA.metric-2 && srvA.metric-1_value < 20'
+                weight: 2",
+        );
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|x| x.value).collect::<Vec<u8>>(),
+            vec![2, 0, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_compiled_expressions() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [10.0, 160], [95.0, 220], [95.0, 280]]},
+                    {"target": "srvA.metric-2", "datapoints": [[95.0, 100], [10.0, 160], [95.0, 220], [10.0, 280]]}
+                ])
+                .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let expressions = "
+              - expression: 'srvA.metric-1 || srvA.metric-2_value > 90'
+                weight: 1
+              - expression: 'srvA.metric-1 && srvA.metric-2'
+                weight: 2
+              - expression: 'if(srvA.metric-2, 1, 0) + if(srvA.metric-1_value > 90, 1, 0)'
+                mode: numeric";
+        let compiled = get_expressions_test_state(server.url().as_str(), expressions);
+        assert!(compiled.health_metrics["srvA"]
+            .expressions
+            .iter()
+            .all(|x| x.node.is_some()));
+        // Same configuration evaluated from the expression strings
+        let mut interpreted = get_expressions_test_state(server.url().as_str(), expressions);
+        for expr in interpreted
+            .health_metrics
+            .get_mut("srvA")
+            .unwrap()
+            .expressions
+            .iter_mut()
+        {
+            expr.node = None;
+        }
+
+        let res_compiled =
+            common::get_service_health(&compiled, "srvA", "env1", "-5min", "-2min", 10, None)
+                .await
+                .unwrap();
+        let res_interpreted =
+            common::get_service_health(&interpreted, "srvA", "env1", "-5min", "-2min", 10, None)
+                .await
+                .unwrap();
+        assert_eq!(res_compiled, res_interpreted);
+        assert_eq!(
+            res_compiled.iter().map(|x| x.value).collect::<Vec<u8>>(),
+            vec![2, 1, 2, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_service_health_keyword_operators() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100], [10.0, 160], [95.0, 220]]},
+                    {"target": "srvA.metric-2", "datapoints": [[95.0, 100], [10.0, 160], [95.0, 220]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_expressions_test_state(
+            server.url().as_str(),
+            "
+              - expression: 'srvA.metric-1 AND NOT srvA.metric-2'
+                weight: 1
+              - expression: 'srvA.metric-1 AND srvA.metric-2'
+                weight: 2",
+        );
+
+        let res = common::get_service_health(&state, "srvA", "env1", "-5min", "-2min", 10, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.iter().map(|x| x.value).collect::<Vec<u8>>(),
+            vec![2, 1, 0]
+        );
+    }
 }
@@ -7,7 +7,7 @@
 //!   url: 'https:/a.b'
 //! server:
 //!   port: 3005
-//! templates:
+//! metric_templates:
 //!   tmpl1:
 //!     query: dummy_query
 //!     op: lt
@@ -35,11 +35,15 @@
 //!         weight: 1
 //! ```
 
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, Utc,
+};
 use glob::glob;
 
 use serde::Deserialize;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
     path::Path,
 };
@@ -65,6 +69,27 @@ pub struct Config {
     pub health_metrics: HashMap<String, ServiceHealthDef>,
     /// Status Dashboard connection
     pub status_dashboard: Option<StatusDashboardConfig>,
+    /// Reaction on duplicate or out of range health expression weights
+    #[serde(default)]
+    pub weight_validation: ValidationLevel,
+    /// Telemetry of the convertor itself
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    /// Format of the log output
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Log an event whenever a flag metric turns unhealthy
+    #[serde(default)]
+    pub log_flag_transitions: bool,
+    /// Status Dashboard reporter settings
+    #[serde(default)]
+    pub reporter: ReporterConfig,
+    /// Reject unknown configuration keys instead of ignoring them
+    #[serde(default)]
+    pub strict_config: bool,
+    /// Configuration keys not understood by the processor
+    #[serde(skip)]
+    pub unknown_keys: Vec<String>,
 }
 
 impl Config {
@@ -74,24 +99,33 @@ impl Config {
     pub fn new(config_file: &str) -> Result<Self, ConfigError> {
         let path = Path::new(config_file)
             .canonicalize()
-            .expect("Can not resolve path to the config.yaml");
+            .map_err(|_| ConfigError::NotFound(config_file.to_string()))?;
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| ConfigError::Message(format!("Invalid config path {:?}", path)))?;
         let mut s = config::Config::builder()
             // Start off by merging in the requested configuration file
-            .add_source(File::with_name(path.to_str().unwrap()));
+            .add_source(File::with_name(path_str));
 
         // Read and merge conf.d config parts
         let configs_glob = format!(
             "{}/conf.d/*.yaml",
             path.parent()
-                .expect("Need parent to config.yaml")
-                .to_str()
-                .unwrap()
+                .and_then(Path::to_str)
+                .ok_or_else(|| ConfigError::Message(format!(
+                    "Invalid config directory for {:?}",
+                    path
+                )))?
         );
         tracing::trace!("Analyzing {:?} as conf.d parts", configs_glob);
-        for entry in glob(configs_glob.as_str()).unwrap() {
+        let entries =
+            glob(configs_glob.as_str()).map_err(|e| ConfigError::Message(e.to_string()))?;
+        for entry in entries {
             tracing::debug!("Add {:?} config part file", entry);
             if let Ok(path) = entry {
-                s = s.add_source(File::with_name(path.to_str().unwrap()));
+                if let Some(part) = path.to_str() {
+                    s = s.add_source(File::with_name(part));
+                }
             }
         }
 
@@ -103,25 +137,47 @@ impl Config {
                 .separator("__"),
         );
 
-        s.build()?.try_deserialize()
+        Self::deserialize_source(s.build()?)
     }
 
     /// Returns a configuration object from a string representing configuration file
-    #[allow(dead_code)]
-    pub fn from_config_str(data: &str) -> Self {
-        let s = config::Config::builder()
+    pub fn from_config_str(data: &str) -> Result<Self, ConfigError> {
+        config::Config::builder()
             .add_source(File::from_str(data, config::FileFormat::Yaml))
             .build()
-            .unwrap();
-        s.try_deserialize().unwrap()
+            .and_then(Self::deserialize_source)
     }
 
-    /// Returns socket address to use for binding
-    pub fn get_socket_addr(&self) -> SocketAddr {
-        SocketAddr::from((
-            self.server.address.as_str().parse::<IpAddr>().unwrap(),
-            self.server.port,
-        ))
+    /// Deserialize the merged configuration sources remembering and logging unknown keys. With
+    /// `strict_config` unknown keys are an error.
+    fn deserialize_source(source: config::Config) -> Result<Self, ConfigError> {
+        let mut unknown_keys: Vec<String> = Vec::new();
+        let mut config: Config =
+            serde_ignored::deserialize(source, |path| unknown_keys.push(path.to_string()))?;
+        if config.strict_config && !unknown_keys.is_empty() {
+            return Err(ConfigError::Message(format!(
+                "Unknown configuration keys: {}",
+                unknown_keys.join(", ")
+            )));
+        }
+        for key in unknown_keys.iter() {
+            tracing::warn!("Unknown configuration key {} is ignored", key);
+        }
+        config.unknown_keys = unknown_keys;
+        config.server.bind_address = config.server.parse_bind_address()?;
+        config.server.validate_cors_allow_origins()?;
+        config.datasource.tz_offset = config.datasource.parse_tz_offset()?;
+        config.datasource.validate_date_format()?;
+        config.reporter.validate_impact_map()?;
+        Ok(config)
+    }
+
+    /// Returns host (IP or hostname resolved when binding) and port to bind to
+    pub fn get_bind_address(&self) -> (&str, u16) {
+        (
+            self.server.bind_address.0.as_str(),
+            self.server.bind_address.1,
+        )
     }
 }
 
@@ -133,6 +189,56 @@ pub struct Datasource {
     /// query timeout
     #[serde(default = "default_timeout")]
     pub timeout: u16,
+    /// Seconds to cache identical render responses (0 - disabled)
+    #[serde(default)]
+    pub cache_ttl_secs: u16,
+    /// Ask Graphite to omit null datapoints (`noNullPoints=true`)
+    #[serde(default)]
+    pub no_null_points: bool,
+    /// Serve Graphite `/functions` from the datasource instead of the built-in list
+    #[serde(default)]
+    pub proxy_functions: bool,
+    /// strftime format of the absolute `from`/`until` times sent to the TSDB
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+    /// UTC offset (i.e. `+02:00`) of the TSDB the absolute times are converted to (UTC by default)
+    pub tz: Option<String>,
+    /// Parsed `tz`
+    #[serde(skip, default = "utc_offset")]
+    pub tz_offset: FixedOffset,
+    /// How the series of the render requests are named
+    #[serde(default)]
+    pub alias_mode: AliasMode,
+    /// Attribute series with unexpected names by their position when the response contains as
+    /// many series as requested
+    #[serde(default)]
+    pub positional_fallback: bool,
+    /// Whether the datapoint timestamps denote the start or the end of their bucket
+    #[serde(default)]
+    pub timestamp_alignment: TimestampAlignment,
+}
+
+impl Datasource {
+    /// Parse `tz` into the fixed offset. `UTC`, `Z` or an absent value mean UTC.
+    fn parse_tz_offset(&self) -> Result<FixedOffset, ConfigError> {
+        match self.tz.as_deref().map(str::trim) {
+            None | Some("UTC") | Some("utc") | Some("Z") => Ok(utc_offset()),
+            Some(tz) => tz
+                .parse::<FixedOffset>()
+                .map_err(|_| ConfigError::Message(format!("invalid datasource.tz '{}'", tz))),
+        }
+    }
+
+    /// Verify `date_format` does not contain unsupported specifiers
+    fn validate_date_format(&self) -> Result<(), ConfigError> {
+        if StrftimeItems::new(&self.date_format).any(|x| matches!(x, Item::Error)) {
+            return Err(ConfigError::Message(format!(
+                "invalid datasource.date_format '{}'",
+                self.date_format
+            )));
+        }
+        Ok(())
+    }
 }
 
 /// Server binding configuration
@@ -144,6 +250,108 @@ pub struct ServerConf {
     /// Port to bind to
     #[serde(default = "default_port")]
     pub port: u16,
+    /// maxDataPoints of the Graphite render requests not specifying it
+    #[serde(default = "default_max_data_points")]
+    pub default_max_data_points: u16,
+    /// Token required as `Authorization: Bearer` header on the API requests
+    pub api_token: Option<String>,
+    /// Require the `api_token` also on the Graphite compatible endpoints
+    #[serde(default)]
+    pub api_token_graphite: bool,
+    /// Seconds after which a request is aborted with 504 (0 - no limit)
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Maximal size of the request body accepted by the Graphite endpoints
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// `Cache-Control: max-age` of the health responses, also enables `ETag` (0 - disabled)
+    #[serde(default)]
+    pub health_cache_max_age_secs: u32,
+    /// Maximal number of /health/batch items evaluated concurrently
+    #[serde(default = "default_health_batch_concurrency")]
+    pub health_batch_concurrency: usize,
+    /// Maximal number of items accepted in a single /health/batch request
+    #[serde(default = "default_health_batch_max_items")]
+    pub health_batch_max_items: usize,
+    /// Origins allowed to call the API from the browser (`*` for any), CORS is disabled when empty
+    #[serde(default)]
+    pub cors_allow_origins: Vec<String>,
+    /// TLS termination, plain HTTP is served when absent
+    pub tls: Option<TlsConfig>,
+    /// Host and port to bind to, validated during configuration loading
+    #[serde(skip)]
+    pub bind_address: (String, u16),
+}
+
+/// TLS certificate of the server
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    /// Path to the PEM encoded certificate (chain)
+    pub cert_path: String,
+    /// Path to the PEM encoded private key
+    pub key_path: String,
+}
+
+impl ServerConf {
+    /// Split `address` into the host and port. The address may be an IP (IPv6 optionally in
+    /// brackets) or a hostname, optionally followed by `:port` which takes precedence over `port`.
+    /// Only the syntax is verified, hostnames are resolved when binding.
+    fn parse_bind_address(&self) -> Result<(String, u16), ConfigError> {
+        let address = self.address.trim();
+        let invalid = || ConfigError::Message(format!("invalid server.address '{}'", address));
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            return Ok((addr.ip().to_string(), addr.port()));
+        }
+        let host = address.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok((ip.to_string(), self.port));
+        }
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>().map_err(|_| invalid())?),
+            None => (address, self.port),
+        };
+        if !is_valid_hostname(host) {
+            return Err(invalid());
+        }
+        Ok((host.to_string(), port))
+    }
+
+    /// Verify every entry of `cors_allow_origins` is `*` or an origin (`scheme://host[:port]`)
+    fn validate_cors_allow_origins(&self) -> Result<(), ConfigError> {
+        for origin in self.cors_allow_origins.iter() {
+            let valid = origin == "*"
+                || origin
+                    .parse::<http::Uri>()
+                    .map(|uri| {
+                        uri.scheme().is_some()
+                            && uri.authority().is_some()
+                            && uri.path_and_query().is_none_or(|x| x.as_str() == "/")
+                            && !origin.ends_with('/')
+                    })
+                    .unwrap_or(false);
+            if !valid {
+                return Err(ConfigError::Message(format!(
+                    "invalid server.cors_allow_origins entry '{}'",
+                    origin
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the name is a syntactically valid hostname (dot separated labels of letters, digits
+/// and inner hyphens)
+fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
 }
 
 fn default_address() -> String {
@@ -154,10 +362,38 @@ fn default_port() -> u16 {
     3000
 }
 
+fn default_max_data_points() -> u16 {
+    100
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_health_batch_concurrency() -> usize {
+    8
+}
+
+fn default_health_batch_max_items() -> usize {
+    100
+}
+
 fn default_timeout() -> u16 {
     10
 }
 
+fn default_date_format() -> String {
+    "%H:%M_%Y%m%d".to_string()
+}
+
+fn utc_offset() -> FixedOffset {
+    FixedOffset::east_opt(0).expect("zero offset is valid")
+}
+
 /// TSDB supported types enum
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -166,18 +402,304 @@ pub enum DatasourceType {
     Graphite,
 }
 
+/// Log output format
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// JSON object per line
+    Json,
+}
+
+/// Telemetry configuration
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TelemetryConfig {
+    /// Expose Prometheus metrics under /metrics
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// Reaction on configuration issues that do not prevent processing
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationLevel {
+    /// Log a warning and continue
+    #[default]
+    Warn,
+    /// Refuse the configuration
+    Error,
+}
+
+/// How the series names of the render requests are requested from the TSDB
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasMode {
+    /// Wrap every query into `alias(query,'name')`
+    #[default]
+    Alias,
+    /// Send queries as they are and map the returned series back by the query string
+    None,
+}
+
+/// Which point of the bucket the reported datapoint timestamps denote
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampAlignment {
+    /// Timestamps are reported as returned by the TSDB (start of the bucket)
+    #[default]
+    Start,
+    /// Timestamps are shifted by the bucket width to the end of the bucket
+    End,
+}
+
+/// Behavior when a metric of the health expression is not available in the environment
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingMetrics {
+    /// Refuse to evaluate the service health in the environment
+    #[default]
+    Strict,
+    /// Treat the unavailable metric as not raised and log a warning
+    Lenient,
+}
+
+/// Status Dashboard API used by the reporter
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusDashboardApi {
+    /// Status of the component (name and attributes) is posted to `component_status_path`,
+    /// impact 0 resolves it
+    #[default]
+    V1,
+    /// Incidents of the component ids (`components_path`) are created at `events_path` and
+    /// resolved by patching the created event
+    V2,
+}
+
+/// Reporter configuration
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReporterConfig {
+    /// How the impact is derived from the health datapoints of the window
+    #[serde(default)]
+    pub impact_aggregation: ImpactAggregation,
+    /// Seconds during which the same impact of a component is not reported again (0 - disabled)
+    #[serde(default)]
+    pub incident_cooldown_secs: u64,
+    /// Maximum number of concurrent health checks against the convertor
+    #[serde(default = "default_reporter_concurrency")]
+    pub concurrency: usize,
+    /// Token sent to the convertor API (see `server.api_token`)
+    pub api_token: Option<String>,
+    /// Skip health metrics without `component_name` instead of using the service name as
+    /// component name
+    #[serde(default = "default_require_component_name")]
+    pub require_component_name: bool,
+    /// Base URL of the convertor (`http://localhost:<server.port>` by default)
+    pub convertor_url: Option<String>,
+    /// Attempts to fetch the Status Dashboard components
+    #[serde(default = "default_component_fetch_attempts")]
+    pub component_fetch_attempts: u32,
+    /// Delay before the first retry of the component fetch, doubled with every further retry
+    #[serde(default = "default_component_fetch_backoff_secs")]
+    pub component_fetch_backoff_secs: u64,
+    /// Upper bound of the component fetch retry delay
+    #[serde(default = "default_component_fetch_backoff_max_secs")]
+    pub component_fetch_backoff_max_secs: u64,
+    /// Age after which the cached Status Dashboard components are fetched again
+    #[serde(default = "default_component_cache_ttl_secs")]
+    pub component_cache_ttl_secs: u64,
+    /// Match Status Dashboard components case-insensitive ignoring surrounding whitespace
+    #[serde(default)]
+    pub normalize_component_names: bool,
+    /// Planned maintenances during which no incidents are created
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Status Dashboard impacts of the health weights, unmapped weights are reported as they are
+    #[serde(default)]
+    pub impact_map: Vec<ImpactMapping>,
+}
+
+impl ReporterConfig {
+    /// Verify every weight of `impact_map` is mapped only once
+    fn validate_impact_map(&self) -> Result<(), ConfigError> {
+        let mut weights = HashSet::new();
+        for mapping in self.impact_map.iter() {
+            if !weights.insert(mapping.weight) {
+                return Err(ConfigError::Message(format!(
+                    "reporter.impact_map maps weight {} more than once",
+                    mapping.weight
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Status Dashboard impact reported for the health weight
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ImpactMapping {
+    pub weight: u8,
+    pub impact: u8,
+}
+
+/// Time range during which incidents of the services are suppressed
+#[derive(Clone, Debug, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Start of the window (RFC3339)
+    pub from: DateTime<Utc>,
+    /// End of the window (RFC3339), exclusive
+    pub to: DateTime<Utc>,
+    /// Affected services (health metric names), all services when not set
+    pub services: Option<Vec<String>>,
+}
+
+impl Default for ReporterConfig {
+    fn default() -> Self {
+        Self {
+            impact_aggregation: ImpactAggregation::default(),
+            incident_cooldown_secs: 0,
+            concurrency: default_reporter_concurrency(),
+            api_token: None,
+            require_component_name: default_require_component_name(),
+            convertor_url: None,
+            component_fetch_attempts: default_component_fetch_attempts(),
+            component_fetch_backoff_secs: default_component_fetch_backoff_secs(),
+            component_fetch_backoff_max_secs: default_component_fetch_backoff_max_secs(),
+            component_cache_ttl_secs: default_component_cache_ttl_secs(),
+            normalize_component_names: false,
+            maintenance_windows: Vec::new(),
+            impact_map: Vec::new(),
+        }
+    }
+}
+
+fn default_reporter_concurrency() -> usize {
+    10
+}
+
+fn default_require_component_name() -> bool {
+    true
+}
+
+fn default_component_fetch_attempts() -> u32 {
+    3
+}
+
+fn default_component_fetch_backoff_secs() -> u64 {
+    2
+}
+
+fn default_component_fetch_backoff_max_secs() -> u64 {
+    60
+}
+
+fn default_component_cache_ttl_secs() -> u64 {
+    3600
+}
+
+/// Aggregation of the health datapoints into a single impact
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImpactAggregation {
+    /// Impact of the last datapoint
+    #[default]
+    Last,
+    /// Highest impact over the window
+    Max,
+    /// Highest impact reached by at least half of the datapoints
+    Majority,
+}
+
+/// Signature algorithm of the Status Dashboard JWT token
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub enum JwtAlgorithm {
+    /// HMAC with SHA-256 using shared `secret`
+    #[default]
+    HS256,
+    /// RSA with SHA-256 using `private_key_path`
+    RS256,
+}
+
 /// Status Dashboard configuration
 #[derive(Clone, Debug, Deserialize)]
 pub struct StatusDashboardConfig {
     /// Status dashboard URL
     pub url: String,
-    /// JWT token signature secret
+    /// JWT token signature algorithm
+    #[serde(default)]
+    pub jwt_algorithm: JwtAlgorithm,
+    /// JWT token signature secret (HS256)
     pub secret: Option<String>,
+    /// Path to the PEM encoded RSA private key signing the JWT token (RS256)
+    pub private_key_path: Option<String>,
+    /// `preferred_username` claim of the JWT token
+    pub preferred_username: Option<String>,
+    /// Group put into the `groups` claim of the JWT token
+    pub group: Option<String>,
+    /// Validity of the JWT token in seconds
+    #[serde(default = "default_sd_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+    /// Status Dashboard API used to create and resolve incidents
+    #[serde(default)]
+    pub api_version: StatusDashboardApi,
+    /// API path receiving the component status (v1 API)
+    #[serde(default = "default_sd_component_status_path", alias = "resolve_path")]
+    pub component_status_path: String,
+    /// API path listing the registered components (v2 API)
+    #[serde(default = "default_sd_components_path")]
+    pub components_path: String,
+    /// API path used to create and resolve incidents (v2 API)
+    #[serde(default = "default_sd_events_path")]
+    pub events_path: String,
+    /// Only log incidents instead of creating them
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Highest impact accepted by the Status Dashboard, higher weights are clamped to it
+    #[serde(default = "default_sd_max_impact")]
+    pub max_impact: u8,
+    /// Template of the incident title (`{service}`, `{environment}` and `{impact}` are
+    /// substituted)
+    #[serde(default = "default_sd_incident_title")]
+    pub incident_title: String,
+    /// Template of the incident description
+    #[serde(default = "default_sd_incident_description")]
+    pub incident_description: String,
+}
+
+fn default_sd_token_ttl_secs() -> u64 {
+    300
+}
+
+fn default_sd_component_status_path() -> String {
+    "/api/v1/component_status".to_string()
+}
+
+fn default_sd_max_impact() -> u8 {
+    3
+}
+
+fn default_sd_components_path() -> String {
+    "/v2/components".to_string()
+}
+
+fn default_sd_events_path() -> String {
+    "/v2/events".to_string()
+}
+
+fn default_sd_incident_title() -> String {
+    "System incident from monitoring system".to_string()
+}
+
+fn default_sd_incident_description() -> String {
+    "System-wide incident affecting one or multiple components. Created automatically.".to_string()
 }
 
 #[cfg(test)]
 mod test {
     use crate::config;
+    use config::{ConfigError, StatusDashboardApi};
 
     use std::env;
     use std::fs::{create_dir, File};
@@ -245,6 +767,13 @@ mod test {
       url: abc
     ";
 
+    const INVALID_YAML: &str = "
+    datasource:
+      url: 'https:/a.b'
+    server: [
+      port: 3005
+    ";
+
     const CONFIG_FLAGS: &str = "
     flag_metrics:
       - name: a
@@ -285,6 +814,51 @@ mod test {
         assert_eq!(_config.status_dashboard.unwrap().secret.unwrap(), "val");
     }
 
+    /// Test Status Dashboard API selection and the v1 path alias
+    #[test]
+    fn test_config_status_dashboard_api() {
+        let data = format!("{}flag_metrics: []", CONFIG_PART_STR);
+        let cfg = config::Config::from_config_str(&data).unwrap();
+        let sdb = cfg.status_dashboard.unwrap();
+        assert_eq!(sdb.api_version, StatusDashboardApi::V1);
+        assert_eq!(sdb.component_status_path, "/api/v1/component_status");
+
+        let data = data.replace(
+            "url: abc",
+            "url: abc\n      api_version: v2\n      resolve_path: /status",
+        );
+        let sdb = config::Config::from_config_str(&data)
+            .unwrap()
+            .status_dashboard
+            .unwrap();
+        assert_eq!(sdb.api_version, StatusDashboardApi::V2);
+        assert_eq!(sdb.component_status_path, "/status");
+    }
+
+    /// Test impact map list form and rejection of duplicate weights
+    #[test]
+    fn test_config_impact_map() {
+        let data = format!(
+            "{}flag_metrics: []\n    reporter:\n      impact_map:\n        - {{weight: 2, impact: 5}}\n",
+            CONFIG_PART_STR
+        );
+        let cfg = config::Config::from_config_str(&data).unwrap();
+        assert_eq!(
+            cfg.reporter.impact_map,
+            vec![config::ImpactMapping {
+                weight: 2,
+                impact: 5
+            }]
+        );
+
+        let data = format!("{}        - {{weight: 2, impact: 3}}\n", data);
+        let err = config::Config::from_config_str(&data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "reporter.impact_map maps weight 2 more than once"
+        );
+    }
+
     /// Test merging of the config with conf.d elements
     #[test]
     fn test_merge_parts() {
@@ -294,7 +868,7 @@ mod test {
         let mut main_config_file = File::create(main_config_file_path.clone()).unwrap();
         let confd_file_path = dir.path().join("conf.d");
         create_dir(&confd_file_path).expect("Cannot create tmp/conf.d");
-        let mut flags = File::create(&confd_file_path.as_path().join("flags.yaml")).unwrap();
+        let mut flags = File::create(confd_file_path.as_path().join("flags.yaml")).unwrap();
         println!("flags are {:?}", flags);
 
         main_config_file
@@ -311,4 +885,208 @@ mod test {
 
         dir.close().unwrap();
     }
+
+    /// Test missing config file produces an error
+    #[test]
+    fn test_config_missing_file() {
+        let dir = Builder::new().tempdir().unwrap();
+        let path = dir.path().join("missing.yaml");
+        let err = config::Config::new(path.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ConfigError::NotFound(_)));
+        assert!(err.to_string().contains("missing.yaml"));
+    }
+
+    /// Test malformed yaml produces an error with location details
+    #[test]
+    fn test_config_invalid_yaml() {
+        let mut config_file = Builder::new().suffix(".yaml").tempfile().unwrap();
+        config_file.write_all(INVALID_YAML.as_bytes()).unwrap();
+
+        let err = config::Config::new(config_file.path().to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ConfigError::FileParse { .. }), "{:?}", err);
+        assert!(err.to_string().contains("line"), "{}", err);
+
+        assert!(config::Config::from_config_str(INVALID_YAML).is_err());
+    }
+
+    /// Test unknown keys are remembered, logged and rejected in the strict mode
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_config_unknown_keys() {
+        let data = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        templates:
+          tmpl1:
+            query: dummy1
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics: []
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+        ";
+        let cfg = config::Config::from_config_str(data).unwrap();
+        assert!(cfg.metric_templates.is_none());
+        assert_eq!(cfg.unknown_keys, vec!["templates".to_string()]);
+        assert!(logs_contain(
+            "Unknown configuration key templates is ignored"
+        ));
+
+        let strict = format!("{}strict_config: true\n", data);
+        let err = config::Config::from_config_str(&strict).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown configuration keys: templates");
+    }
+
+    /// Test invalid server address is reported on load
+    #[test]
+    fn test_config_invalid_address() {
+        let data = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          address: 'ADDRESS'
+          port: 3005
+        environments: []
+        flag_metrics: []
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+        ";
+        for address in ["not an ip", "-host", "host:port", "host..local", "a_b", ""] {
+            let err =
+                config::Config::from_config_str(&data.replace("ADDRESS", address)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid server.address '{}'", address)
+            );
+        }
+
+        let cfg = config::Config::from_config_str(&data.replace("ADDRESS", "127.0.0.1")).unwrap();
+        assert_eq!(cfg.get_bind_address(), ("127.0.0.1", 3005));
+    }
+
+    /// Test invalid CORS origins are reported on load
+    #[test]
+    fn test_config_cors_allow_origins() {
+        let data = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+          cors_allow_origins: [ORIGINS]
+        environments: []
+        flag_metrics: []
+        health_metrics: {}
+        ";
+        let load =
+            |origins: &str| config::Config::from_config_str(&data.replace("ORIGINS", origins));
+        let cfg = load("'*', 'https://grafana.example.com', 'http://localhost:3000'").unwrap();
+        assert_eq!(cfg.server.cors_allow_origins.len(), 3);
+        for origin in [
+            "grafana.example.com",
+            "https://grafana.example.com/",
+            "https://grafana.example.com/path",
+            "https://bad host",
+        ] {
+            let err = load(&format!("'{}'", origin)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid server.cors_allow_origins entry '{}'", origin)
+            );
+        }
+    }
+
+    /// Test IPv6, host:port and hostname server addresses
+    #[test]
+    fn test_config_address_forms() {
+        let data = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          address: 'ADDRESS'
+          port: 3005
+        environments: []
+        flag_metrics: []
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+        ";
+        let addr = |address: &str| {
+            let cfg = config::Config::from_config_str(&data.replace("ADDRESS", address)).unwrap();
+            let (host, port) = cfg.get_bind_address();
+            (host.to_string(), port)
+        };
+        assert_eq!(addr("::1"), ("::1".to_string(), 3005));
+        assert_eq!(addr("[::1]"), ("::1".to_string(), 3005));
+        assert_eq!(addr("[::1]:8080"), ("::1".to_string(), 8080));
+        assert_eq!(addr("0.0.0.0"), ("0.0.0.0".to_string(), 3005));
+        assert_eq!(addr("127.0.0.1:8080"), ("127.0.0.1".to_string(), 8080));
+        // Hostnames are not resolved during loading
+        assert_eq!(addr("localhost"), ("localhost".to_string(), 3005));
+        assert_eq!(addr("localhost:8080"), ("localhost".to_string(), 8080));
+        assert_eq!(
+            addr("metrics.example.com"),
+            ("metrics.example.com".to_string(), 3005)
+        );
+    }
+
+    /// Test datasource timezone and date format parsing
+    #[test]
+    fn test_config_datasource_tz() {
+        let data = "
+        datasource:
+          url: 'https:/a.b'
+          DATASOURCE_EXTRA
+        server:
+          port: 3005
+        environments: []
+        flag_metrics: []
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+        ";
+        let load =
+            |extra: &str| config::Config::from_config_str(&data.replace("DATASOURCE_EXTRA", extra));
+
+        let cfg = load("").unwrap();
+        assert_eq!(cfg.datasource.tz_offset.local_minus_utc(), 0);
+        assert_eq!(cfg.datasource.date_format, "%H:%M_%Y%m%d");
+        assert_eq!(
+            load("tz: UTC")
+                .unwrap()
+                .datasource
+                .tz_offset
+                .local_minus_utc(),
+            0
+        );
+        assert_eq!(
+            load("tz: '+02:00'")
+                .unwrap()
+                .datasource
+                .tz_offset
+                .local_minus_utc(),
+            7200
+        );
+        assert_eq!(
+            load("tz: '-05:30'")
+                .unwrap()
+                .datasource
+                .tz_offset
+                .local_minus_utc(),
+            -19800
+        );
+        assert_eq!(
+            load("tz: Europe/Berlin").unwrap_err().to_string(),
+            "invalid datasource.tz 'Europe/Berlin'"
+        );
+        assert_eq!(
+            load("date_format: '%H:%Q'").unwrap_err().to_string(),
+            "invalid datasource.date_format '%H:%Q'"
+        );
+    }
 }
@@ -5,25 +5,36 @@
 use axum::{
     async_trait,
     extract::{FromRequest, Query, State},
-    http::{header::CONTENT_TYPE, Request, StatusCode},
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Request, StatusCode},
     response::{IntoResponse, Response},
     routing::get,
     Form, Json, RequestExt, Router,
 };
 use axum_macros::debug_handler;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use itertools::Itertools;
 //use regex::Regex;
 //use reqwest::Error;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use serde_json::json;
-use std::collections::HashMap;
-// use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::common::{get_metric_flag_state, get_service_health};
-use crate::types::{AppState, CloudMonError};
+use crate::api::get_request_id;
+use crate::common::{
+    add_flag_metric_targets, get_baseline, get_metric_flag_state_with_baseline, get_service_health,
+    parse_time_spec, split_baselines,
+};
+use crate::config::{AliasMode, Datasource, TimestampAlignment};
+use crate::telemetry::Telemetry;
+use crate::types::{AppState, CloudMonError, DatasourceErrorKind, SharedState};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct GraphiteData {
     /// Target name
     pub target: String,
@@ -31,13 +42,163 @@ pub struct GraphiteData {
     pub datapoints: Vec<(Option<f32>, u32)>,
 }
 
+/// In-process cache of the Graphite render responses
+///
+/// Entries are keyed by the full set of render query parameters (targets, time range,
+/// maxDataPoints) and expire after the configured TTL. TTL of 0 disables caching.
+#[derive(Debug, Default)]
+pub struct GraphiteCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Vec<GraphiteData>)>>,
+}
+
+impl GraphiteCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return cached response unless it is expired
+    fn get(&self, key: &str) -> Option<Vec<GraphiteData>> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((created, data)) if created.elapsed() < self.ttl => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// Store response dropping all expired entries
+    fn insert(&self, key: String, data: &[GraphiteData]) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (created, _)| created.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), data.to_vec()));
+    }
+}
+
+/// Graphite path of the OpenStack API metrics composed from its parts:
+/// `<namespace>.<environment>.*.<service>.<operation>.<status>.<metric>`
+///
+/// Environment and service default to the `$environment` and `$service` template variables,
+/// unset operation, status and metric match any node (`count` for the metric).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MetricPath {
+    /// Leading nodes, e.g. `stats.counters.openstack.api`
+    pub namespace: String,
+    #[serde(default = "default_path_environment")]
+    pub environment: String,
+    #[serde(default = "default_path_service")]
+    pub service: String,
+    /// Request method and endpoint nodes
+    #[serde(default = "default_path_operation")]
+    pub operation: String,
+    /// Response status, e.g. `failed`, `attempted` or `{2*,3*,404}`
+    #[serde(default = "default_path_wildcard")]
+    pub status: String,
+    #[serde(default = "default_path_metric")]
+    pub metric: String,
+}
+
+fn default_path_environment() -> String {
+    "$environment".to_string()
+}
+
+fn default_path_service() -> String {
+    "$service".to_string()
+}
+
+fn default_path_operation() -> String {
+    "*.*".to_string()
+}
+
+fn default_path_wildcard() -> String {
+    "*".to_string()
+}
+
+fn default_path_metric() -> String {
+    "count".to_string()
+}
+
+impl MetricPath {
+    pub fn new(namespace: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            environment: default_path_environment(),
+            service: default_path_service(),
+            operation: default_path_operation(),
+            status: default_path_wildcard(),
+            metric: default_path_metric(),
+        }
+    }
+
+    pub fn with_environment(mut self, environment: &str) -> Self {
+        self.environment = environment.to_string();
+        self
+    }
+
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = service.to_string();
+        self
+    }
+
+    pub fn with_operation(mut self, operation: &str) -> Self {
+        self.operation = operation.to_string();
+        self
+    }
+
+    pub fn with_status(mut self, status: &str) -> Self {
+        self.status = status.to_string();
+        self
+    }
+
+    pub fn with_metric(mut self, metric: &str) -> Self {
+        self.metric = metric.to_string();
+        self
+    }
+}
+
+impl fmt::Display for MetricPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.*.{}.{}.{}.{}",
+            self.namespace.trim_end_matches('.'),
+            self.environment,
+            self.service,
+            self.operation,
+            self.status,
+            self.metric
+        )
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MetricsQuery {
     pub query: String,
     pub from: Option<String>,
     pub until: Option<String>,
+    /// Maximal number of returned elements (no limit by default)
+    pub limit: Option<usize>,
+}
+
+/// Elements matching the find query
+#[derive(Debug)]
+pub struct FoundMetrics {
+    pub metrics: Vec<Metric>,
+    /// Whether further elements were cut off by the `limit`
+    pub has_more: bool,
 }
 
+/// Response header telling that the find response has been truncated
+const HAS_MORE_HEADER: &str = "x-has-more";
+
 #[derive(Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct Metric {
     #[serde(rename(serialize = "allowChildren"))]
@@ -48,13 +209,66 @@ pub struct Metric {
     pub text: String,
 }
 
-#[derive(Default, Debug, Deserialize)]
+/// Graphite render request
+///
+/// `target` may be repeated (or given as an array in JSON payload) to render multiple targets.
+#[derive(Default, Debug)]
 pub struct RenderRequest {
-    pub target: Option<String>,
+    pub target: Vec<String>,
     pub from: Option<String>,
     pub until: Option<String>,
-    #[serde(rename(deserialize = "maxDataPoints"))]
     pub max_data_points: Option<u16>,
+    /// Output format: `json` (default) or `csv`
+    pub format: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for RenderRequest {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        struct RenderRequestVisitor;
+
+        impl<'de> Visitor<'de> for RenderRequestVisitor {
+            type Value = RenderRequest;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("render request parameters")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut request = RenderRequest::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "target" => match map.next_value()? {
+                            OneOrMany::One(x) => request.target.push(x),
+                            OneOrMany::Many(x) => request.target.extend(x),
+                        },
+                        "from" => request.from = Some(map.next_value()?),
+                        "until" => request.until = Some(map.next_value()?),
+                        "maxDataPoints" => request.max_data_points = Some(map.next_value()?),
+                        "format" => request.format = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(request)
+            }
+        }
+
+        deserializer.deserialize_map(RenderRequestVisitor)
+    }
 }
 
 #[derive(Default, Debug)]
@@ -91,23 +305,124 @@ where
     }
 }
 
-pub fn get_graphite_routes() -> Router<AppState> {
-    return Router::new()
+pub fn get_graphite_routes() -> Router<SharedState> {
+    Router::new()
         .route("/functions", get(handler_functions))
         .route(
             "/metrics/find",
             get(handler_metrics_find_get).post(handler_metrics_find_post),
         )
         .route("/render", get(handler_render).post(handler_render))
-        .route("/tags/autoComplete/tags", get(handler_tags));
+        .route("/tags/autoComplete/tags", get(handler_tags))
+        .route("/tags/autoComplete/values", get(handler_tag_values))
+}
+
+/// Graphite function descriptor in the format of the `/functions` API
+fn function_descriptor(
+    name: &str,
+    group: &str,
+    description: &str,
+    params: &[(&str, &str, bool)],
+) -> serde_json::Value {
+    json!({
+        "name": name,
+        "function": format!(
+            "{}({})",
+            name,
+            params.iter().map(|(param, _, _)| *param).join(", ")
+        ),
+        "description": description,
+        "module": "graphite.render.functions",
+        "group": group,
+        "params": params
+            .iter()
+            .map(|(param, kind, required)| json!({
+                "name": param,
+                "type": kind,
+                "required": required
+            }))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Functions offered to the Grafana query editor when not proxied to the datasource
+pub fn get_builtin_functions() -> serde_json::Value {
+    let functions = [
+        function_descriptor(
+            "alias",
+            "Alias",
+            "Takes one metric or a wildcard seriesList and a string in quotes. Prints the string instead of the metric name in the legend.",
+            &[("seriesList", "seriesList", true), ("newName", "string", true)],
+        ),
+        function_descriptor(
+            "aliasByNode",
+            "Alias",
+            "Takes a seriesList and applies an alias derived from one or more \"node\" portion/s of the target name.",
+            &[("seriesList", "seriesList", true), ("nodes", "nodeOrTag", true)],
+        ),
+        function_descriptor(
+            "sumSeries",
+            "Combine",
+            "Short form: sum(). This will add metrics together and return the sum at each datapoint.",
+            &[("seriesLists", "seriesList", true)],
+        ),
+        function_descriptor(
+            "asPercent",
+            "Combine",
+            "Calculates a percentage of the total of a wildcard series.",
+            &[
+                ("seriesList", "seriesList", true),
+                ("total", "any", false),
+                ("nodes", "nodeOrTag", false),
+            ],
+        ),
+        function_descriptor(
+            "smartSummarize",
+            "Transform",
+            "Smarter version of summarize. The alignToFrom boolean parameter has been replaced by alignTo.",
+            &[
+                ("seriesList", "seriesList", true),
+                ("intervalString", "interval", true),
+                ("func", "aggFunc", false),
+                ("alignTo", "string", false),
+            ],
+        ),
+    ];
+    serde_json::Value::Object(
+        functions
+            .into_iter()
+            .map(|x| (x["name"].as_str().unwrap().to_string(), x))
+            .collect(),
+    )
 }
 
 /// Handler for graphite list supported functions API
-pub async fn handler_functions() -> impl IntoResponse {
-    (StatusCode::OK, Json(json!({})))
+///
+/// With `datasource.proxy_functions` the list is fetched from the datasource, the built-in
+/// list is used otherwise or when the datasource can not be queried.
+pub async fn handler_functions(State(state): State<SharedState>) -> impl IntoResponse {
+    let (client, url, proxy) = {
+        let state = state.read().await;
+        (
+            state.req_client.clone(),
+            state.config.datasource.url.clone(),
+            state.config.datasource.proxy_functions,
+        )
+    };
+    if proxy {
+        match client.get(format!("{}/functions", url)).send().await {
+            Ok(rsp) if rsp.status().is_success() => match rsp.json::<serde_json::Value>().await {
+                Ok(functions) => return (StatusCode::OK, Json(functions)),
+                Err(e) => tracing::warn!("Cannot parse datasource functions: {}", e),
+            },
+            Ok(rsp) => tracing::warn!("Datasource functions returned {}", rsp.status()),
+            Err(e) => tracing::warn!("Cannot fetch datasource functions: {}", e),
+        }
+    }
+    (StatusCode::OK, Json(get_builtin_functions()))
 }
 
-pub fn find_metrics(find_request: MetricsQuery, state: AppState) -> Vec<Metric> {
+pub fn find_metrics(find_request: MetricsQuery, state: &AppState) -> FoundMetrics {
     tracing::debug!("Processing find query={:?}", find_request);
     tracing::debug!("target={:?}", find_request.query);
     let mut metrics: Vec<Metric> = Vec::new();
@@ -129,13 +444,17 @@ pub fn find_metrics(find_request: MetricsQuery, state: AppState) -> Vec<Metric>
             text: "health".to_string(),
         });
     } else if target_parts.len() == 2 && "*".eq(target_parts[1]) {
-        for env in state.environments.iter() {
+        for name in state
+            .environments
+            .iter()
+            .flat_map(|env| std::iter::once(&env.name).chain(env.aliases.iter()))
+        {
             metrics.push(Metric {
                 allow_children: 1,
                 expandable: 1,
                 leaf: 0,
-                id: env.name.clone(),
-                text: env.name.clone(),
+                id: name.clone(),
+                text: name.clone(),
             });
         }
     } else {
@@ -203,76 +522,302 @@ pub fn find_metrics(find_request: MetricsQuery, state: AppState) -> Vec<Metric>
                 id: x.clone(),
                 text: x.clone(),
             }));
+            // Categories of the health metrics can be expanded to their services
+            metrics.extend(
+                state
+                    .health_metrics
+                    .values()
+                    .map(|x| x.category.as_str())
+                    .unique()
+                    .map(|x| Metric {
+                        allow_children: 1,
+                        expandable: 1,
+                        leaf: 0,
+                        id: x.to_string(),
+                        text: x.to_string(),
+                    }),
+            );
+        } else if target_parts.len() == 4 && "health".eq(target_parts[0]) {
+            // Returning health metrics of the category
+            metrics.extend(
+                find_category_services(state, target_parts[2], target_parts[3])
+                    .into_iter()
+                    .map(|x| Metric {
+                        allow_children: 0,
+                        expandable: 0,
+                        leaf: 1,
+                        id: x.to_string(),
+                        text: x.to_string(),
+                    }),
+            );
         }
         tracing::debug!("Elements {:?}", target_parts);
     }
-    return metrics;
+    // Deterministic order independent of the hash based state, no duplicate ids
+    metrics.sort_by(|a, b| Ord::cmp(&a.text, &b.text).then_with(|| Ord::cmp(&a.id, &b.id)));
+    metrics.dedup_by(|a, b| a.id == b.id && a.leaf == b.leaf);
+    let mut has_more = false;
+    if let Some(limit) = find_request.limit {
+        has_more = metrics.len() > limit;
+        metrics.truncate(limit);
+    }
+    FoundMetrics { metrics, has_more }
+}
+
+/// Sorted names of the health metrics matching `service` (name or `*`) in the `category` (name
+/// or `*`)
+fn find_category_services<'a>(state: &'a AppState, category: &str, service: &str) -> Vec<&'a str> {
+    state
+        .health_metrics
+        .iter()
+        .filter(|(name, hm)| {
+            ("*".eq(category) || hm.category == category) && ("*".eq(service) || *name == service)
+        })
+        .map(|(name, _)| name.as_str())
+        .sorted()
+        .collect()
+}
+
+/// Convert found elements into the find response. Truncated results are marked with the
+/// `X-Has-More: true` header to keep the body compatible with Graphite.
+fn find_response(found: FoundMetrics) -> Response {
+    let mut response = (StatusCode::OK, Json(json!(found.metrics))).into_response();
+    if found.has_more {
+        response
+            .headers_mut()
+            .insert(HAS_MORE_HEADER, HeaderValue::from_static("true"));
+    }
+    response
 }
 
 /// POST Handler for graphite find metrics API
 #[debug_handler]
 pub async fn handler_metrics_find_post(
-    State(state): State<AppState>,
+    State(state): State<SharedState>,
     JsonOrForm(query): JsonOrForm<MetricsQuery>,
 ) -> impl IntoResponse {
     tracing::debug!("Processing find query={:?}", query);
-    let metrics: Vec<Metric> = find_metrics(query, state);
-    return (
-        StatusCode::OK,
-        Json(json!(metrics
-            .into_iter()
-            .sorted_by(|a, b| Ord::cmp(&a.text, &b.text))
-            .collect::<Vec<Metric>>())),
-    );
+    find_response(find_metrics(query, &*state.read().await))
 }
 
 /// GET Handler for graphite find metrics API
 #[debug_handler]
 pub async fn handler_metrics_find_get(
-    State(state): State<AppState>,
+    State(state): State<SharedState>,
     Query(query): Query<MetricsQuery>,
 ) -> impl IntoResponse {
     tracing::debug!("Processing find query={:?}", query);
-    let metrics: Vec<Metric> = find_metrics(query, state);
-    return (
-        StatusCode::OK,
-        Json(json!(metrics
-            .into_iter()
-            .sorted_by(|a, b| Ord::cmp(&a.text, &b.text))
-            .collect::<Vec<Metric>>())),
-    );
+    find_response(find_metrics(query, &*state.read().await))
 }
 
 /// Handler for graphite render API
 #[debug_handler]
 pub async fn handler_render(
     query: Option<Query<RenderRequest>>,
-    State(state): State<AppState>,
+    State(state): State<SharedState>,
+    headers: HeaderMap,
     payload: Option<JsonOrForm<RenderRequest>>,
-) -> impl IntoResponse {
+) -> Response {
+    let state = state.read().await;
     let Query(query) = query.unwrap_or_default();
-    let target = match payload {
-        Some(JsonOrForm(ref x)) => x.target.as_ref().expect("Target is required"),
-        None => query.target.as_ref().expect("Target is required"),
+    let request = match payload {
+        Some(JsonOrForm(x)) => x,
+        None => query,
     };
-    let max_data_points = match payload {
-        Some(JsonOrForm(ref x)) => x.max_data_points.expect(" is required"),
-        None => query.max_data_points.expect("Query is required"),
+    if request.target.is_empty() {
+        return render_bad_request("target is required").into_response();
+    }
+    if let (Some(from), Some(until)) = (request.from.as_ref(), request.until.as_ref()) {
+        // Only compare times we understand, the rest is left to the datasource
+        if let (Ok(from_ts), Ok(until_ts)) = (parse_time_spec(from), parse_time_spec(until)) {
+            if from_ts > until_ts {
+                return render_bad_request(
+                    format!("from ({}) must not be after until ({})", from, until).as_str(),
+                )
+                .into_response();
+            }
+        }
+    }
+    let csv = match request.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => {
+            return render_bad_request(format!("format {} is not supported", format).as_str())
+                .into_response()
+        }
     };
-    let from: Option<String> = match payload {
-        Some(JsonOrForm(ref x)) => x.from.clone(),
-        None => query.from.clone(),
+    let max_data_points = request
+        .max_data_points
+        .unwrap_or(state.config.server.default_max_data_points);
+
+    let ctx = RenderContext {
+        from: request.from.clone(),
+        until: request.until.clone(),
+        max_data_points,
+        request_id: get_request_id(&headers),
     };
-    let to: Option<String> = match payload {
-        Some(JsonOrForm(ref x)) => x.until.clone(),
-        None => query.until.clone(),
+    let mut result: Vec<GraphiteData> = Vec::new();
+    for target in request.target.iter() {
+        match render_target(&state, target, &ctx).await {
+            Ok(data) => result.extend(data),
+            Err(response) => return response.into_response(),
+        }
+    }
+    if csv {
+        return (
+            StatusCode::OK,
+            [(CONTENT_TYPE, "text/csv")],
+            graphite_data_to_csv(&result),
+        )
+            .into_response();
+    }
+    (StatusCode::OK, Json(json!(result))).into_response()
+}
+
+/// Serialize series as Graphite CSV (`target,timestamp,value` lines, empty value for nulls)
+fn graphite_data_to_csv(data: &[GraphiteData]) -> String {
+    let mut csv = String::new();
+    for series in data.iter() {
+        for (value, ts) in series.datapoints.iter() {
+            let date = Utc
+                .timestamp_opt(*ts as i64, 0)
+                .single()
+                .map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+            let value = value.map(|x| x.to_string()).unwrap_or_default();
+            csv.push_str(format!("{},{},{}\r\n", series.target, date, value).as_str());
+        }
+    }
+    csv
+}
+
+/// Parse the `seriesByTag('tag=value', ...)` target into tag => value. Only exact matches
+/// (`=`) are supported.
+fn parse_series_by_tag(target: &str) -> Option<HashMap<&str, &str>> {
+    let args = target
+        .trim()
+        .strip_prefix("seriesByTag(")?
+        .strip_suffix(')')?;
+    args.split(',')
+        .map(|arg| {
+            let arg = arg.trim().trim_matches(|c| c == '\'' || c == '"');
+            let (tag, value) = arg.split_once('=')?;
+            if tag.ends_with('!') || value.starts_with('~') {
+                return None;
+            }
+            Some((tag.trim(), value.trim()))
+        })
+        .collect()
+}
+
+/// Time range and options shared by all targets of the render request
+struct RenderContext<'a> {
+    from: Option<String>,
+    until: Option<String>,
+    max_data_points: u16,
+    request_id: Option<&'a str>,
+}
+
+impl RenderContext<'_> {
+    /// Time range required to render health
+    fn required_range(&self) -> Result<(&str, &str), (StatusCode, Json<serde_json::Value>)> {
+        match (&self.from, &self.until) {
+            (Some(from), Some(until)) => Ok((from, until)),
+            _ => Err(render_bad_request("from and until are required")),
+        }
+    }
+}
+
+/// Render health of the services in the environment naming the series with `name`
+async fn render_health<F>(
+    state: &AppState,
+    environment: &str,
+    services: Vec<&str>,
+    ctx: &RenderContext<'_>,
+    name: F,
+) -> Result<Vec<GraphiteData>, (StatusCode, Json<serde_json::Value>)>
+where
+    F: Fn(&str) -> String,
+{
+    let (from, until) = ctx.required_range()?;
+    let mut result: Vec<GraphiteData> = Vec::new();
+    for service in services {
+        match get_service_health(
+            state,
+            service,
+            environment,
+            from,
+            until,
+            ctx.max_data_points,
+            ctx.request_id,
+        )
+        .await
+        {
+            Ok(service_health_data) => result.push(GraphiteData {
+                target: name(service),
+                datapoints: service_health_data
+                    .iter()
+                    .map(|x| (Some(x.value as f32), x.ts))
+                    .collect(),
+            }),
+            Err(CloudMonError::GraphiteError { .. }) => {
+                return Err(render_datasource_error());
+            }
+            Err(e) => {
+                tracing::debug!("Cannot render health of {}: {}", service, e);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Render the tagged health target `seriesByTag('name=health', 'environment=<env>', ...)`.
+/// `service` and `category` tags narrow the rendered services (all by default), series are
+/// named `health;category=<category>;environment=<env>;service=<service>`.
+async fn render_tagged_target(
+    state: &AppState,
+    tags: HashMap<&str, &str>,
+    ctx: &RenderContext<'_>,
+) -> Result<Vec<GraphiteData>, (StatusCode, Json<serde_json::Value>)> {
+    if tags.get("name") != Some(&"health") {
+        return Ok(Vec::new());
+    }
+    let environment = match tags.get("environment") {
+        Some(environment) => *environment,
+        None => return Err(render_bad_request("environment tag is required")),
     };
+    let services = find_category_services(
+        state,
+        tags.get("category").unwrap_or(&"*"),
+        tags.get("service").unwrap_or(&"*"),
+    );
+    render_health(state, environment, services, ctx, |service| {
+        format!(
+            "health;category={};environment={};service={}",
+            state.health_metrics[service].category, environment, service
+        )
+    })
+    .await
+}
 
+/// Render single target of the render request
+async fn render_target(
+    state: &AppState,
+    target: &str,
+    ctx: &RenderContext<'_>,
+) -> Result<Vec<GraphiteData>, (StatusCode, Json<serde_json::Value>)> {
+    if let Some(tags) = parse_series_by_tag(target) {
+        tracing::trace!("Render tagged series for {:?}", target);
+        return render_tagged_target(state, tags, ctx).await;
+    }
     let target_parts: Vec<&str> = target.split(".").collect();
     match target_parts[0] {
-        "flag" => {
+        // `raw` renders the values of the flag metric before comparing them with the threshold
+        "flag" | "raw" => {
             tracing::trace!("Render flags for {:?}", target);
+            let raw = target_parts[0] == "raw";
             let mut graphite_targets: HashMap<String, String> = HashMap::new();
+            let mut no_alias_targets: HashSet<String> = HashSet::new();
             if target_parts.len() == 4 {
                 let environment = target_parts[1];
                 let metric_name = format!("{}.{}", target_parts[2], target_parts[3]);
@@ -281,33 +826,46 @@ pub async fn handler_render(
                     for (metric, metric_map) in state.flag_metrics.iter() {
                         if metric.starts_with(target) {
                             if let Some(m) = metric_map.get(environment) {
-                                graphite_targets.insert(metric.clone(), m.query.clone());
+                                add_flag_metric_targets(
+                                    &mut graphite_targets,
+                                    &mut no_alias_targets,
+                                    metric,
+                                    m,
+                                );
                             }
                         }
                     }
                 } else if let Some(metric) = state.flag_metrics.get(&metric_name) {
-                    match metric.get(environment) {
-                        Some(m) => {
-                            graphite_targets.insert(metric_name.clone(), m.query.clone());
-                        }
-                        _ => {}
+                    if let Some(m) = metric.get(environment) {
+                        add_flag_metric_targets(
+                            &mut graphite_targets,
+                            &mut no_alias_targets,
+                            &metric_name,
+                            m,
+                        );
                     };
                 }
                 tracing::debug!("Requesting Graphite {:?}", graphite_targets);
 
                 match get_graphite_data(
-                    &state.req_client,
-                    &state.config.datasource.url.as_str(),
+                    &FetchContext::from_state(state),
                     &graphite_targets,
-                    None,
-                    from,
-                    None,
-                    to,
-                    max_data_points,
+                    &TimeRange {
+                        from: ctx.from.clone().map(TimeBound::Raw),
+                        until: ctx.until.clone().map(TimeBound::Raw),
+                    },
+                    ctx.max_data_points,
+                    &QueryOptions::from(&state.config.datasource)
+                        .with_request_id(ctx.request_id)
+                        .with_no_alias_targets(no_alias_targets),
                 )
                 .await
                 {
-                    Ok(mut raw_data) => {
+                    Ok(raw_data) => {
+                        let (mut raw_data, baselines) = split_baselines(raw_data);
+                        if raw {
+                            return Ok(raw_data);
+                        }
                         for data_element in raw_data.iter_mut() {
                             // target + datapoints
                             tracing::trace!("Processing dataframe {:?}", data_element);
@@ -319,9 +877,17 @@ pub async fn handler_render(
                                         metric_cfg
                                     );
                                     let metric = metric_cfg.get(environment).unwrap();
+                                    if state.config.datasource.no_null_points {
+                                        // Gaps stay gaps instead of becoming lowered flags
+                                        data_element.datapoints.retain(|(val, _)| val.is_some());
+                                    }
                                     // Iterate over all fetched series
-                                    for (val, _) in data_element.datapoints.iter_mut() {
-                                        *val = if get_metric_flag_state(val, metric) {
+                                    for (val, ts) in data_element.datapoints.iter_mut() {
+                                        let baseline =
+                                            get_baseline(&baselines, &data_element.target, *ts);
+                                        *val = if get_metric_flag_state_with_baseline(
+                                            val, baseline, metric,
+                                        ) {
                                             Some(1.0)
                                         } else {
                                             Some(0.0)
@@ -337,129 +903,617 @@ pub async fn handler_render(
                             }
                         }
 
-                        return (StatusCode::OK, Json(json!(raw_data)));
-                    }
-                    Err(_) => {
-                        return (
-                            StatusCode::OK,
-                            Json(json!({"message": "Error reading data from TSDB"})),
-                        )
+                        return Ok(raw_data);
                     }
+                    Err(_) => return Err(render_datasource_error()),
                 };
             }
         }
         "health" => {
             tracing::trace!("Render Health for {:?}", target);
-            if target_parts.len() == 3 {
-                let from = from.unwrap();
-                let to = to.unwrap();
-                if let Ok(service_health_data) = get_service_health(
-                    &state,
-                    target_parts[2],
-                    target_parts[1],
-                    from.as_str(),
-                    to.as_str(),
-                    max_data_points,
-                )
-                .await
-                {
-                    return (
-                        StatusCode::OK,
-                        Json(
-                            json!([{"target": target_parts[2], "datapoints": service_health_data.iter().map(|x| (Some(x.1 as f32), x.0)).collect::<Vec<(Option<f32>, u32)>>()}]),
-                        ),
-                    );
-                }
+            if target_parts.len() == 3 || target_parts.len() == 4 {
+                // Wildcard expands into all known services
+                let services: Vec<&str> = if target_parts.len() == 4 {
+                    // health.<env>.<category>.<service>
+                    find_category_services(state, target_parts[2], target_parts[3])
+                } else if "*".eq(target_parts[2]) {
+                    state
+                        .health_metrics
+                        .keys()
+                        .map(String::as_str)
+                        .sorted()
+                        .collect()
+                } else {
+                    vec![target_parts[2]]
+                };
+                return render_health(state, target_parts[1], services, ctx, |service| {
+                    service.to_string()
+                })
+                .await;
             }
         }
         _ => {}
     }
+    Ok(Vec::new())
+}
+
+/// Response to the render request which failed because of the datasource. Grafana shows the
+/// `message` of non-2xx responses as query error.
+fn render_datasource_error() -> (StatusCode, Json<serde_json::Value>) {
     (
-        StatusCode::OK,
-        //Json(json!([{"target": "", "datapoints": []}])),
-        Json(json!([])),
+        StatusCode::BAD_GATEWAY,
+        Json(json!({"message": "Error reading data from TSDB"})),
     )
 }
 
+/// Response to the render request with invalid parameters
+fn render_bad_request(message: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({ "message": message })))
+}
+
+/// Wrap query into `alias()` escaping quotes and backslashes of the name
 fn alias_graphite_query(query: &str, alias: &str) -> String {
-    format!("alias({},'{}')", query, alias)
+    format!(
+        "alias({},'{}')",
+        query,
+        alias.replace('\\', "\\\\").replace('\'', "\\'")
+    )
+}
+
+/// Rename series returned for not aliased targets back to the target names.
+///
+/// Series are matched by the query string first. Remaining unknown series are then attributed
+/// by their order to the not aliased targets left in the order of the request, but only when
+/// there are exactly as many of them as unmatched targets. With the
+/// `positional_fallback` series still not known are finally attributed to the target at the
+/// same position of the request, as long as the response contains as many series as requested.
+fn restore_target_names(
+    data: &mut [GraphiteData],
+    targets: &[(&String, &String)],
+    options: &QueryOptions,
+) {
+    let mut unmatched: Vec<&String> = targets
+        .iter()
+        .filter(|(name, _)| !options.is_aliased(name))
+        .map(|(name, _)| *name)
+        .filter(|name| !data.iter().any(|x| x.target == **name))
+        .collect();
+    let mut unknown: Vec<usize> = Vec::new();
+    for (idx, series) in data.iter_mut().enumerate() {
+        if targets.iter().any(|(name, _)| **name == series.target) {
+            continue;
+        }
+        match targets
+            .iter()
+            .find(|(name, query)| **query == series.target && unmatched.contains(name))
+        {
+            Some((name, _)) => {
+                unmatched.retain(|x| x != name);
+                series.target = (*name).clone();
+            }
+            None => unknown.push(idx),
+        }
+    }
+    if unknown.len() != unmatched.len() {
+        if !unknown.is_empty() && !unmatched.is_empty() {
+            tracing::warn!(
+                "Cannot attribute {} unknown series to {} not aliased targets by position",
+                unknown.len(),
+                unmatched.len()
+            );
+        }
+        unknown.clear();
+    }
+    for (idx, name) in unknown.into_iter().zip(unmatched) {
+        tracing::warn!(
+            "Attributing series {} to {} by position",
+            data[idx].target,
+            name
+        );
+        data[idx].target = name.clone();
+    }
+    if options.positional_fallback && data.len() == targets.len() {
+        for idx in 0..data.len() {
+            let name = targets[idx].0;
+            if targets.iter().any(|(x, _)| **x == data[idx].target)
+                || data.iter().any(|x| x.target == *name)
+            {
+                continue;
+            }
+            tracing::warn!(
+                "Unexpected series {} attributed to {} by position",
+                data[idx].target,
+                name
+            );
+            data[idx].target = name.clone();
+        }
+    }
+}
+
+/// Datasource specific options of the render requests
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    /// Send `noNullPoints=true`
+    pub no_null_points: bool,
+    /// strftime format of the absolute times
+    pub date_format: String,
+    /// Offset the absolute times are converted to before formatting
+    pub tz_offset: FixedOffset,
+    /// Id of the API request the query is issued for
+    pub request_id: Option<String>,
+    /// How the series are named
+    pub alias_mode: AliasMode,
+    /// Targets sent without the `alias()` wrapper regardless of the `alias_mode`
+    pub no_alias_targets: HashSet<String>,
+    /// Attribute unexpected series by position
+    pub positional_fallback: bool,
+    /// Which point of the bucket the timestamps denote
+    pub timestamp_alignment: TimestampAlignment,
+}
+
+impl QueryOptions {
+    /// Send the targets without the `alias()` wrapper
+    pub fn with_no_alias_targets(mut self, targets: HashSet<String>) -> Self {
+        self.no_alias_targets = targets;
+        self
+    }
+
+    /// Whether the target is wrapped into `alias()`
+    fn is_aliased(&self, target: &str) -> bool {
+        self.alias_mode == AliasMode::Alias && !self.no_alias_targets.contains(target)
+    }
+
+    /// Attach id of the originating API request
+    pub fn with_request_id(mut self, request_id: Option<&str>) -> Self {
+        self.request_id = request_id.map(str::to_string);
+        self
+    }
+
+    /// Format absolute time in the datasource zone
+    fn format_time(&self, time: &DateTime<FixedOffset>) -> String {
+        time.with_timezone(&self.tz_offset)
+            .format(&self.date_format)
+            .to_string()
+    }
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        Self {
+            no_null_points: false,
+            date_format: "%H:%M_%Y%m%d".to_string(),
+            tz_offset: FixedOffset::east_opt(0).expect("zero offset is valid"),
+            request_id: None,
+            alias_mode: AliasMode::default(),
+            no_alias_targets: HashSet::new(),
+            positional_fallback: false,
+            timestamp_alignment: TimestampAlignment::default(),
+        }
+    }
+}
+
+impl From<&Datasource> for QueryOptions {
+    fn from(datasource: &Datasource) -> Self {
+        Self {
+            no_null_points: datasource.no_null_points,
+            date_format: datasource.date_format.clone(),
+            tz_offset: datasource.tz_offset,
+            request_id: None,
+            alias_mode: datasource.alias_mode,
+            no_alias_targets: HashSet::new(),
+            positional_fallback: datasource.positional_fallback,
+            timestamp_alignment: datasource.timestamp_alignment,
+        }
+    }
+}
+
+/// Bucket width of the response: the smallest spacing of the datapoints over all series. When no
+/// series has two datapoints it is derived from the requested range and `maxDataPoints`.
+fn bucket_width(data: &[GraphiteData], range: &TimeRange, max_data_points: u16) -> Option<u32> {
+    data.iter()
+        .flat_map(|series| {
+            series
+                .datapoints
+                .windows(2)
+                .map(|x| x[1].1.saturating_sub(x[0].1))
+        })
+        .filter(|x| *x > 0)
+        .min()
+        .or_else(|| {
+            let range =
+                (range.until.as_ref()?.time()? - range.from.as_ref()?.time()?).num_seconds();
+            if range <= 0 || max_data_points == 0 {
+                return None;
+            }
+            u32::try_from((range + i64::from(max_data_points) - 1) / i64::from(max_data_points))
+                .ok()
+        })
+}
+
+/// Shift timestamps of all series by the bucket width so that they denote the end of the bucket
+fn align_to_bucket_end(data: &mut [GraphiteData], width: u32) {
+    for series in data.iter_mut() {
+        for (_, ts) in series.datapoints.iter_mut() {
+            *ts = ts.saturating_add(width);
+        }
+    }
+}
+
+/// Bound of the time range of the render request
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimeBound {
+    /// Absolute time formatted according to the datasource settings
+    Time(DateTime<FixedOffset>),
+    /// Time passed to Graphite as is (i.e. `-5min` or `now`)
+    Raw(String),
+}
+
+impl TimeBound {
+    /// RFC3339 time becomes absolute, anything else is passed to Graphite as is
+    pub fn parse(value: &str) -> Self {
+        match DateTime::parse_from_rfc3339(value) {
+            Ok(time) => TimeBound::Time(time),
+            Err(_) => TimeBound::Raw(value.to_string()),
+        }
+    }
+
+    /// Absolute time of the bound
+    fn time(&self) -> Option<DateTime<FixedOffset>> {
+        match self {
+            TimeBound::Time(time) => Some(*time),
+            TimeBound::Raw(_) => None,
+        }
+    }
+
+    /// Value of the bound in the render request
+    fn format(&self, options: &QueryOptions) -> String {
+        match self {
+            TimeBound::Time(time) => options.format_time(time),
+            TimeBound::Raw(value) => value.clone(),
+        }
+    }
+}
+
+/// Time range of the render request, missing bounds are left to Graphite defaults
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeRange {
+    pub from: Option<TimeBound>,
+    pub until: Option<TimeBound>,
+}
+
+impl TimeRange {
+    /// Range between the parsed `from` and `until` (see [`TimeBound::parse`])
+    pub fn parse(from: &str, until: &str) -> Self {
+        Self {
+            from: Some(TimeBound::parse(from)),
+            until: Some(TimeBound::parse(until)),
+        }
+    }
+}
+
+/// Graphite the render requests are sent to, with the optional response cache and telemetry
+#[derive(Clone, Copy)]
+pub struct FetchContext<'a> {
+    pub client: &'a reqwest::Client,
+    pub url: &'a str,
+    pub cache: Option<&'a GraphiteCache>,
+    pub telemetry: Option<&'a Telemetry>,
+}
+
+impl<'a> FetchContext<'a> {
+    /// Context without cache and telemetry
+    pub fn new(client: &'a reqwest::Client, url: &'a str) -> Self {
+        Self {
+            client,
+            url,
+            cache: None,
+            telemetry: None,
+        }
+    }
+
+    /// Context of the configured datasource using the cache and telemetry of the state
+    pub fn from_state(state: &'a AppState) -> Self {
+        Self {
+            client: &state.req_client,
+            url: state.config.datasource.url.as_str(),
+            cache: Some(&state.graphite_cache),
+            telemetry: state.telemetry.as_deref(),
+        }
+    }
+
+    /// Use the response cache
+    pub fn with_cache(mut self, cache: Option<&'a GraphiteCache>) -> Self {
+        self.cache = cache;
+        self
+    }
 }
 
 /// Fetch required data from Graphite
+#[tracing::instrument(
+    name = "graphite_fetch",
+    skip_all,
+    fields(request_id = options.request_id.as_deref())
+)]
 pub async fn get_graphite_data(
-    client: &reqwest::Client,
-    url: &str,
+    fetch: &FetchContext<'_>,
     targets: &HashMap<String, String>,
-    from: Option<DateTime<FixedOffset>>,
-    from_raw: Option<String>,
-    to: Option<DateTime<FixedOffset>>,
-    to_raw: Option<String>,
+    range: &TimeRange,
     max_data_points: u16,
+    options: &QueryOptions,
 ) -> Result<Vec<GraphiteData>, CloudMonError> {
+    tracing::debug!("Fetching {} targets from Graphite", targets.len());
     // Prepare vector of query parameters
     let mut query_params: Vec<(_, String)> = [
         ("format", "json".to_string()),
-        // ("noNullPoints", "true".to_string()),
         ("maxDataPoints", max_data_points.to_string()),
     ]
     .into();
-    if let Some(xfrom) = from {
-        query_params.push(("from", xfrom.format("%H:%M_%Y%m%d").to_string()));
-    } else if let Some(xfrom) = from_raw {
-        query_params.push(("from", xfrom.clone()));
-    }
-    if let Some(xto) = to {
-        query_params.push(("until", xto.format("%H:%M_%Y%m%d").to_string()));
-    } else if let Some(xto) = to_raw {
-        query_params.push(("until", xto.clone()));
-    }
-    query_params.extend(
-        targets
-            .iter()
-            .map(|x| ("target", alias_graphite_query(x.1, x.0))),
-    );
+    if options.no_null_points {
+        query_params.push(("noNullPoints", "true".to_string()));
+    }
+    if let Some(from) = &range.from {
+        query_params.push(("from", from.format(options)));
+    }
+    if let Some(until) = &range.until {
+        query_params.push(("until", until.format(options)));
+    }
+    // Explicit order of the targets in the request is needed to match not aliased series
+    let targets: Vec<(&String, &String)> = targets.iter().sorted().collect();
+    query_params.extend(targets.iter().map(|(name, query)| {
+        (
+            "target",
+            if options.is_aliased(name) {
+                alias_graphite_query(query, name)
+            } else {
+                query.to_string()
+            },
+        )
+    }));
     tracing::trace!("Query: {:?}", &query_params);
-    let res = client
-        .get(format!("{}/render", url))
+    let cache_key = query_params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .sorted()
+        .join("&");
+    if let Some(data) = fetch.cache.and_then(|x| x.get(&cache_key)) {
+        tracing::debug!("Serving Graphite response from cache");
+        return Ok(data);
+    }
+    let _timer = fetch.telemetry.map(|x| {
+        x.graphite_requests.inc();
+        x.graphite_latency.start_timer()
+    });
+    let res = fetch
+        .client
+        .get(format!("{}/render", fetch.url))
         .query(&query_params)
         .send()
         .await;
-    match res {
+    let result = match res {
         Ok(rsp) => {
-            if rsp.status().is_client_error() {
-                tracing::error!("Error: {:?}", rsp.text().await);
-                return Err(CloudMonError::GraphiteError);
+            let status = rsp.status();
+            if !status.is_success() {
+                let body = rsp.text().await.unwrap_or_default();
+                Err(graphite_error(
+                    DatasourceErrorKind::Status,
+                    Some(status.as_u16()),
+                    format!("[{}] {}", status, truncate_message(body.trim())),
+                    &query_params,
+                ))
             } else {
-                tracing::trace!("Status: {}", rsp.status());
+                tracing::trace!("Status: {}", status);
                 tracing::trace!("Headers:\n{:#?}", rsp.headers());
-                match rsp.json().await {
-                    Ok(dt) => return Ok(dt),
-                    Err(_) => return Err(CloudMonError::GraphiteError),
+                match rsp.json::<Vec<GraphiteData>>().await {
+                    Ok(mut dt) => {
+                        restore_target_names(&mut dt, &targets, options);
+                        if options.timestamp_alignment == TimestampAlignment::End {
+                            if let Some(width) = bucket_width(&dt, range, max_data_points) {
+                                align_to_bucket_end(&mut dt, width);
+                            }
+                        }
+                        if let Some(cache) = fetch.cache {
+                            cache.insert(cache_key, &dt);
+                        }
+                        Ok(dt)
+                    }
+                    Err(e) => Err(graphite_error(
+                        DatasourceErrorKind::InvalidResponse,
+                        Some(status.as_u16()),
+                        format!("invalid response: {}", e),
+                        &query_params,
+                    )),
                 }
             }
         }
-        Err(_) => return Err(CloudMonError::GraphiteError),
+        Err(e) => Err(graphite_error(
+            DatasourceErrorKind::NoResponse,
+            None,
+            if e.is_timeout() {
+                "request timed out".to_string()
+            } else {
+                format!("request failed: {}", e)
+            },
+            &query_params,
+        )),
     };
+    if let (Err(_), Some(telemetry)) = (&result, fetch.telemetry) {
+        telemetry.graphite_errors.inc();
+    }
+    result
 }
-///
-/// Handler for graphite tags API
-#[debug_handler]
-pub async fn handler_tags() -> impl IntoResponse {
-    (StatusCode::OK, Json(json!([])))
+
+/// Maximal length of the Graphite response body kept in the logged error
+const MAX_ERROR_MESSAGE_LEN: usize = 200;
+
+fn truncate_message(message: &str) -> String {
+    match message.char_indices().nth(MAX_ERROR_MESSAGE_LEN) {
+        Some((idx, _)) => format!("{}...", &message[..idx]),
+        None => message.to_string(),
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::*;
-    use mockito::Matcher;
-    use reqwest::ClientBuilder;
-    // use std::sync::Arc;
-    use chrono::{DateTime, FixedOffset};
-    use std::time::Duration;
+/// Generic message of the failed datasource request returned to the API clients. Details
+/// (response body, URL) are only logged.
+fn datasource_error_message(kind: DatasourceErrorKind, status: Option<u16>) -> String {
+    match (kind, status) {
+        (DatasourceErrorKind::InvalidResponse, _) => "invalid datasource response".to_string(),
+        (_, Some(status)) => format!("datasource request failed (status {})", status),
+        (_, None) => "datasource request failed (no response)".to_string(),
+    }
+}
 
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
+/// Build and log the error of the failed Graphite render request
+fn graphite_error(
+    kind: DatasourceErrorKind,
+    status: Option<u16>,
+    details: String,
+    query_params: &[(&str, String)],
+) -> CloudMonError {
+    let targets: Vec<String> = query_params
+        .iter()
+        .filter(|(k, _)| *k == "target")
+        .map(|(_, v)| v.clone())
+        .collect();
+    tracing::error!(status, targets = ?targets, "Graphite request failed: {}", details);
+    CloudMonError::GraphiteError {
+        kind,
+        status,
+        message: datasource_error_message(kind, status),
+        targets,
+    }
+}
+
+/// Check that the Graphite datasource is reachable by issuing a cheap `/metrics/find` request
+pub async fn probe_datasource(client: &reqwest::Client, url: &str) -> Result<(), CloudMonError> {
+    match client
+        .get(format!("{}/metrics/find", url))
+        .query(&[("query", "*")])
+        .send()
+        .await
+    {
+        Ok(rsp) if rsp.status().is_success() => Ok(()),
+        Ok(rsp) => {
+            tracing::warn!("Datasource probe returned {}", rsp.status());
+            Err(CloudMonError::GraphiteError {
+                kind: DatasourceErrorKind::Status,
+                status: Some(rsp.status().as_u16()),
+                message: datasource_error_message(
+                    DatasourceErrorKind::Status,
+                    Some(rsp.status().as_u16()),
+                ),
+                targets: Vec::new(),
+            })
+        }
+        Err(e) => {
+            tracing::warn!("Datasource probe failed: {}", e);
+            Err(CloudMonError::GraphiteError {
+                kind: DatasourceErrorKind::NoResponse,
+                status: None,
+                message: datasource_error_message(DatasourceErrorKind::NoResponse, None),
+                targets: Vec::new(),
+            })
+        }
+    }
+}
+/// Tags of the health series (`name` and the configured dimensions)
+const TAGS: [&str; 4] = ["category", "environment", "name", "service"];
+
+/// Query parameters of the tags auto-completion
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagsQuery {
+    pub tag_prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Query parameters of the tag values auto-completion
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagValuesQuery {
+    pub tag: String,
+    pub value_prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Sorted values of the tag known in the configuration
+fn get_tag_values(state: &AppState, tag: &str) -> BTreeSet<String> {
+    match tag {
+        // Only the health series are addressable by tags
+        "name" => BTreeSet::from(["health".to_string()]),
+        "service" => state.health_metrics.keys().cloned().collect(),
+        "category" => state
+            .health_metrics
+            .values()
+            .map(|x| x.category.clone())
+            .collect(),
+        "environment" => state
+            .environments
+            .iter()
+            .map(|x| x.name.clone())
+            .chain(state.flag_metrics.values().flat_map(|x| x.keys().cloned()))
+            .collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
+/// Apply prefix filter and limit to the sorted auto-completion candidates
+fn filter_completions<'a, I>(values: I, prefix: Option<&str>, limit: Option<usize>) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    values
+        .into_iter()
+        .filter(|x| x.starts_with(prefix.unwrap_or_default()))
+        .take(limit.unwrap_or(usize::MAX))
+        .map(String::from)
+        .collect()
+}
+
+///
+/// Handler for graphite tags API
+#[debug_handler]
+pub async fn handler_tags(query: Option<Query<TagsQuery>>) -> impl IntoResponse {
+    let Query(query) = query.unwrap_or_default();
+    (
+        StatusCode::OK,
+        Json(filter_completions(
+            TAGS,
+            query.tag_prefix.as_deref(),
+            query.limit,
+        )),
+    )
+}
+
+/// Handler for graphite tag values API
+pub async fn handler_tag_values(
+    State(state): State<SharedState>,
+    Query(query): Query<TagValuesQuery>,
+) -> impl IntoResponse {
+    let state = state.read().await;
+    let values = get_tag_values(&state, query.tag.as_str());
+    (
+        StatusCode::OK,
+        Json(filter_completions(
+            values.iter().map(String::as_str),
+            query.value_prefix.as_deref(),
+            query.limit,
+        )),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use chrono::{DateTime, FixedOffset};
+    use mockito::Matcher;
+    use reqwest::ClientBuilder;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::RwLock;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
     };
     use serde_json::{json, Value};
     use tower::Service; // for `call`
@@ -467,9 +1521,77 @@ mod test {
 
     use std::collections::HashMap;
 
+    #[test]
+    fn test_metric_path() {
+        let api = graphite::MetricPath::new("stats.counters.openstack.api");
+        assert_eq!(
+            api.clone().with_status("failed").to_string(),
+            "stats.counters.openstack.api.$environment.*.$service.*.*.failed.count"
+        );
+        assert_eq!(
+            api.with_environment("production")
+                .with_service("compute")
+                .with_status("{2*,3*,404}")
+                .to_string(),
+            "stats.counters.openstack.api.production.*.compute.*.*.{2*,3*,404}.count"
+        );
+        assert_eq!(
+            graphite::MetricPath::new("stats.timers.openstack.api.")
+                .with_operation("GET.servers")
+                .with_metric("mean")
+                .to_string(),
+            "stats.timers.openstack.api.$environment.*.$service.GET.servers.*.mean"
+        );
+    }
+
     #[test]
     fn test_alias_graphite_query() {
         assert_eq!(graphite::alias_graphite_query("q", "n"), "alias(q,'n')");
+        assert_eq!(
+            graphite::alias_graphite_query("q", "it's"),
+            "alias(q,'it\\'s')"
+        );
+        assert_eq!(
+            graphite::alias_graphite_query("q", "a\\b"),
+            "alias(q,'a\\\\b')"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_graphite_data_alias_disabled() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::UrlEncoded(
+                "target".into(),
+                "sumSeries(a.*)".into(),
+            ))
+            .with_body(
+                json!([{"target": "sumSeries(a.*)", "datapoints": [[1.0, 100]]}]).to_string(),
+            )
+            .create_async()
+            .await;
+        let mut targets: HashMap<String, String> = HashMap::new();
+        targets.insert("srvA.metric-1".to_string(), "sumSeries(a.*)".to_string());
+
+        let res = graphite::get_graphite_data(
+            &graphite::FetchContext::new(
+                &ClientBuilder::new().build().unwrap(),
+                server.url().as_str(),
+            ),
+            &targets,
+            &graphite::TimeRange::parse("-5min", "-2min"),
+            15,
+            &graphite::QueryOptions {
+                alias_mode: config::AliasMode::None,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        mock.assert_async().await;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].target, "srvA.metric-1");
     }
 
     macro_rules! aw {
@@ -491,7 +1613,7 @@ mod test {
                 Matcher::UrlEncoded("maxDataPoints".into(), "15".into()),
             ]))
             .create();
-        let timeout = Duration::from_secs(1 as u64);
+        let timeout = Duration::from_secs(1);
         let _req_client: reqwest::Client = ClientBuilder::new().timeout(timeout).build().unwrap();
 
         let mut targets: HashMap<String, String> = HashMap::new();
@@ -502,69 +1624,391 @@ mod test {
             DateTime::parse_from_rfc3339("2022-02-01T00:00:00+00:00").ok();
         let max_data_points: u16 = 15;
         let _res = aw!(graphite::get_graphite_data(
-            &_req_client,
-            format!("{}", server.url()).as_str(),
+            &graphite::FetchContext::new(&_req_client, server.url().as_str()),
             &targets,
-            from,
-            None,
-            to,
-            None,
+            &graphite::TimeRange {
+                from: from.map(graphite::TimeBound::Time),
+                until: to.map(graphite::TimeBound::Time)
+            },
             max_data_points,
+            &graphite::QueryOptions::default()
         ));
         mock.assert();
     }
 
     #[tokio::test]
-    async fn test_get_grafana_find() {
-        let f = "
-        datasource:
-          url: 'https:/a.b'
-        server:
-          port: 3005
-        metric_templates:
-          tmpl1:
-            query: dummy1($environment.$service.count)
-            op: lt
-            threshold: 90
-          tmpl2:
-            query: dummy2($environment.$service.count)
-            op: gt
-            threshold: 80
-        environments:
-          - name: env1
-        flag_metrics:
-          - name: metric-1
-            service: srvA
-            template:
-              name: tmpl1
-            environments:
-              - name: env1
-              - name: env2
-                threshold: 1
-          - name: metric-2
-            service: srvA
-            template:
-              name: tmpl2
-            environments:
-              - name: env1
-              - name: env2
-        health_metrics:
-          srvA:
-            service: srvA
-            category: compute
-            metrics:
-              - srvA.metric-1
-              - srvA.metric-2
-            expressions:
-              - expression: 'srvA.metric-1 || srvA.metric-2'
-                weight: 1
-";
-        let config = config::Config::from_config_str(f);
-        let mut state = types::AppState::new(config);
-        state.process_config();
+    async fn test_get_graphite_data_cache() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .expect(1)
+            .match_query(Matcher::Any)
+            .with_body(json!([{"target": "alias", "datapoints": [[1.0, 1]]}]).to_string())
+            .create_async()
+            .await;
+        let req_client = ClientBuilder::new().build().unwrap();
+        let cache = graphite::GraphiteCache::new(Duration::from_secs(60));
+
+        let mut targets: HashMap<String, String> = HashMap::new();
+        targets.insert("alias".to_string(), "query".to_string());
+        for _ in 0..2 {
+            let res = graphite::get_graphite_data(
+                &graphite::FetchContext::new(&req_client, server.url().as_str())
+                    .with_cache(Some(&cache)),
+                &targets,
+                &graphite::TimeRange::parse("-5min", "-2min"),
+                15,
+                &graphite::QueryOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(res[0].target, "alias");
+        }
+        mock.assert_async().await;
+    }
+
+    /// Prepare state with srvA metric-1 (tmpl1, lt 90) and metric-2 (tmpl2, gt 80) flags in env1
+    /// and env2 and srvA health in env1 using given Graphite URL
+    fn get_flags_test_state(url: &str) -> types::SharedState {
+        let state = test_utils::get_test_state_with(url, |config| {
+            config.flag_metrics[0]
+                .environments
+                .push(types::MetricEnvironmentDef {
+                    name: "env2".to_string(),
+                    threshold: Some(1.0),
+                    epsilon: None,
+                });
+            test_utils::add_test_metric_2(config);
+            config.health_metrics.get_mut("srvA").unwrap().expressions[0].expression =
+                "srvA.metric-1 || srvA.metric-2".to_string();
+        })
+        .unwrap();
+        Arc::new(RwLock::new(state))
+    }
+
+    #[tokio::test]
+    async fn test_render_missing_params() {
+        let app = graphite::get_graphite_routes().with_state(get_flags_test_state("https://a.b"));
+        for (uri, message) in [
+            ("/render?maxDataPoints=10", "target is required"),
+            (
+                "/render?target=health.env1.srvA&maxDataPoints=10",
+                "from and until are required",
+            ),
+            (
+                "/render?target=health.env1.srvA&from=-2min&until=-5min",
+                "from (-2min) must not be after until (-5min)",
+            ),
+            (
+                "/render?target=flag.env1.srvA.metric-1&from=2022-02-01T00:00:00Z&until=2022-01-01T00:00:00Z",
+                "from (2022-02-01T00:00:00Z) must not be after until (2022-01-01T00:00:00Z)",
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, json!({ "message": message }));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_multiple_targets() {
+        let mut server = mockito::Server::new_async().await;
+        // health of srvA requires both metrics
+        let _health_mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Regex("metric-2".to_string()))
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100]]},
+                    {"target": "srvA.metric-2", "datapoints": [[10.0, 100]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let _flag_mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[95.0, 100]]}]).to_string(),
+            )
+            .create_async()
+            .await;
+        let app = graphite::get_graphite_routes().with_state(get_flags_test_state(&server.url()));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/render?target=flag.env1.srvA.metric-1&target=health.env1.srvA&from=-5min&until=now&maxDataPoints=10")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!([
+                {"target": "srvA.metric-1", "datapoints": [[0.0, 100]]},
+                {"target": "srvA", "datapoints": [[1.0, 100]]}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_raw() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [
+                    [10.0, 100], [95.0, 160], [null, 220]
+                ]}])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = graphite::get_graphite_routes().with_state(get_flags_test_state(&server.url()));
+        let render = |target: &str| {
+            let request = Request::builder()
+                .uri(format!(
+                    "/render?target={}.env1.srvA.metric-1&from=-5min&until=now&maxDataPoints=10",
+                    target
+                ))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+
+        assert_eq!(
+            render("flag").await,
+            json!([{"target": "srvA.metric-1", "datapoints": [
+                [1.0, 100], [0.0, 160], [0.0, 220]
+            ]}])
+        );
+        assert_eq!(
+            render("raw").await,
+            json!([{"target": "srvA.metric-1", "datapoints": [
+                [10.0, 100], [95.0, 160], [null, 220]
+            ]}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_default_max_data_points() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::UrlEncoded(
+                "maxDataPoints".to_string(),
+                "100".to_string(),
+            ))
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[95.0, 100]]}]).to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let app = graphite::get_graphite_routes().with_state(get_flags_test_state(&server.url()));
+
+        for request in [
+            Request::builder()
+                .uri("/render?target=flag.env1.srvA.metric-1&from=-5min&until=now")
+                .body(Body::empty())
+                .unwrap(),
+            Request::builder()
+                .method("POST")
+                .uri("/render")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(
+                    "target=flag.env1.srvA.metric-1&from=-5min&until=now",
+                ))
+                .unwrap(),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                body,
+                json!([{"target": "srvA.metric-1", "datapoints": [[0.0, 100]]}])
+            );
+        }
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_render_csv() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([{"target": "srvA.metric-1", "datapoints": [[95.0, 1640995200], [10.0, 1640995260]]}])
+                    .to_string(),
+            )
+            .create_async()
+            .await;
+        let app = graphite::get_graphite_routes().with_state(get_flags_test_state(&server.url()));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/render?target=flag.env1.srvA.metric-1&from=-5min&until=now&format=csv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "srvA.metric-1,2022-01-01 00:00:00,0\r\nsrvA.metric-1,2022-01-01 00:01:00,1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_health_wildcard() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100]]},
+                    {"target": "srvA.metric-2", "datapoints": [[10.0, 100]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_flags_test_state(&server.url());
+        {
+            // srvB with the same health definition
+            let mut state = state.write().await;
+            let health = state.health_metrics["srvA"].clone();
+            state.health_metrics.insert("srvB".to_string(), health);
+        }
+        let app = graphite::get_graphite_routes().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/render?target=health.env1.*&from=-5min&until=now&maxDataPoints=10")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!([
+                {"target": "srvA", "datapoints": [[1.0, 100]]},
+                {"target": "srvB", "datapoints": [[1.0, 100]]}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_series_by_tag() {
+        assert_eq!(
+            graphite::parse_series_by_tag("seriesByTag('name=health', \"service=srvA\")"),
+            Some(HashMap::from([("name", "health"), ("service", "srvA")]))
+        );
+        assert_eq!(
+            graphite::parse_series_by_tag("seriesByTag('name=health','service!=srvA')"),
+            None
+        );
+        assert_eq!(
+            graphite::parse_series_by_tag("seriesByTag('name=health', 'service=~srv.*')"),
+            None
+        );
+        assert_eq!(graphite::parse_series_by_tag("health.env1.srvA"), None);
+    }
+
+    #[tokio::test]
+    async fn test_render_series_by_tag() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100]]},
+                    {"target": "srvA.metric-2", "datapoints": [[10.0, 100]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = get_flags_test_state(&server.url());
+        let app = graphite::get_graphite_routes().with_state(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(
+                        "/render?target=seriesByTag(%27name%3Dhealth%27%2C%20%27service%3DsrvA%27%2C%20%27environment%3Denv1%27)&from=-5min&until=now&maxDataPoints=10",
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!([{
+                "target": "health;category=compute;environment=env1;service=srvA",
+                "datapoints": [[1.0, 100]]
+            }])
+        );
+    }
+
+    #[test]
+    fn test_render_request_deserialize() {
+        let uri = "/render?target=a&target=b&maxDataPoints=5&format=json"
+            .parse()
+            .unwrap();
+        let axum::extract::Query(request) =
+            axum::extract::Query::<graphite::RenderRequest>::try_from_uri(&uri).unwrap();
+        assert_eq!(request.target, vec!["a", "b"]);
+        assert_eq!(request.max_data_points, Some(5));
+        let request: graphite::RenderRequest =
+            serde_json::from_value(json!({"target": ["a", "b"], "from": "-5min"})).unwrap();
+        assert_eq!(request.target, vec!["a", "b"]);
+        assert_eq!(request.from.as_deref(), Some("-5min"));
+        let request: graphite::RenderRequest =
+            serde_json::from_value(json!({"target": "a", "maxDataPoints": 10})).unwrap();
+        assert_eq!(request.target, vec!["a"]);
+    }
 
-        // let app_state = Arc::new(state);
-        let mut app = graphite::get_graphite_routes().with_state(state);
+    #[tokio::test]
+    async fn test_get_grafana_find() {
+        let app_state = get_flags_test_state("https:/a.b");
+        let mut app = graphite::get_graphite_routes().with_state(app_state);
 
         let request_all = Request::builder()
             .uri("/metrics/find?query=*")
@@ -657,7 +2101,620 @@ mod test {
         let body: Value = serde_json::from_slice(&body).unwrap();
         assert_eq!(
             body,
-            json!([{"allowChildren": 0, "expandable": 0, "id": "srvA", "leaf": 1, "text": "srvA"}])
+            json!([
+                {"allowChildren": 1, "expandable": 1, "id": "compute", "leaf": 0, "text": "compute"},
+                {"allowChildren": 0, "expandable": 0, "id": "srvA", "leaf": 1, "text": "srvA"}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_health_category() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "srvA.metric-1", "datapoints": [[10.0, 100]]},
+                    {"target": "srvB.metric-1", "datapoints": [[10.0, 100]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let state = test_utils::get_test_state(server.url().as_str());
+        let get_json = |uri: &str| {
+            let app = graphite::get_graphite_routes().with_state(state.clone());
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+        let leaf = |name: &str| json!({"allowChildren": 0, "expandable": 0, "id": name, "leaf": 1, "text": name});
+        let node = |name: &str| json!({"allowChildren": 1, "expandable": 1, "id": name, "leaf": 0, "text": name});
+
+        // 3-part form lists the categories next to the services
+        assert_eq!(
+            get_json("/metrics/find?query=health.env1.*").await,
+            json!([node("compute"), node("network"), leaf("srvA"), leaf("srvB")])
+        );
+        for (query, expected) in [
+            ("health.env1.compute.*", json!([leaf("srvA")])),
+            ("health.env1.network.*", json!([leaf("srvB")])),
+            ("health.env1.*.*", json!([leaf("srvA"), leaf("srvB")])),
+            ("health.env1.network.srvB", json!([leaf("srvB")])),
+            ("health.env1.compute.srvB", json!([])),
+            ("health.env1.storage.*", json!([])),
+        ] {
+            assert_eq!(
+                get_json(format!("/metrics/find?query={}", query).as_str()).await,
+                expected,
+                "{}",
+                query
+            );
+        }
+
+        let body = get_json("/render?target=health.env1.network.*&from=-5min&until=-2min").await;
+        assert_eq!(
+            body,
+            json!([{"target": "srvB", "datapoints": [[2.0, 100]]}])
+        );
+    }
+
+    #[test]
+    fn test_find_metrics_sorted() {
+        let mut flag_metrics = String::new();
+        for service in ["srvD", "srvB", "srvC", "srvA"] {
+            flag_metrics.push_str(
+                format!(
+                    "
+          - name: metric-2
+            service: {service}
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+              - name: env2
+          - name: metric-1
+            service: {service}
+            template:
+              name: tmpl1
+            environments:
+              - name: env2"
+                )
+                .as_str(),
+            );
+        }
+        let data = format!(
+            "
+        datasource:
+          url: 'https://a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1
+            op: lt
+            threshold: 90
+        environments:
+          - name: env2
+          - name: env1
+        flag_metrics:{}
+        health_metrics: {{}}
+",
+            flag_metrics
+        );
+        let find = |query: &str| {
+            let mut state = types::AppState::new(config::Config::from_config_str(&data).unwrap());
+            state.process_config().unwrap();
+            graphite::find_metrics(
+                graphite::MetricsQuery {
+                    query: query.to_string(),
+                    from: None,
+                    until: None,
+                    limit: None,
+                },
+                &state,
+            )
+            .metrics
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<String>>()
+        };
+
+        for _ in 0..5 {
+            assert_eq!(find("flag.env1.*"), ["srvA", "srvB", "srvC", "srvD"]);
+            assert_eq!(find("flag.*"), ["env1", "env2"]);
+            assert_eq!(find("flag.env2.srvC.*"), ["metric-1", "metric-2"]);
+        }
+    }
+
+    #[test]
+    fn test_find_metrics_disabled() {
+        let config = "
+        datasource:
+          url: 'https://a.b'
+        server:
+          port: 3005
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            query: dummy1
+            op: lt
+            threshold: 90
+            environments:
+              - name: env1
+          - name: metric-2
+            service: srvA
+            query: dummy2
+            op: lt
+            threshold: 90
+            disabled: true
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+              - expression: 'srvA.metric_1'
+                weight: 1
+          srvB:
+            service: srvB
+            category: compute
+            metrics: []
+            expressions: []
+            disabled: true
+";
+        let mut state = types::AppState::new(config::Config::from_config_str(config).unwrap());
+        state.process_config().unwrap();
+        let find = |query: &str| {
+            graphite::find_metrics(
+                graphite::MetricsQuery {
+                    query: query.to_string(),
+                    from: None,
+                    until: None,
+                    limit: None,
+                },
+                &state,
+            )
+            .metrics
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<String>>()
+        };
+
+        assert_eq!(find("flag.env1.srvA.*"), ["metric-1"]);
+        assert!(find("flag.env1.srvA.metric-2").is_empty());
+        assert_eq!(find("health.env1.*"), ["compute", "srvA"]);
+        assert_eq!(find("health.env1.compute.*"), ["srvA"]);
+    }
+
+    #[tokio::test]
+    async fn test_find_metrics_limit() {
+        let state = get_flags_test_state("https://a.b");
+
+        for (uri, expected, has_more) in [
+            (
+                "/metrics/find?query=flag.env1.srvA.*&limit=1",
+                json!(["metric-1"]),
+                true,
+            ),
+            (
+                "/metrics/find?query=flag.env1.srvA.*&limit=2",
+                json!(["metric-1", "metric-2"]),
+                false,
+            ),
+            (
+                "/metrics/find?query=flag.env1.srvA.*",
+                json!(["metric-1", "metric-2"]),
+                false,
+            ),
+        ] {
+            let app = graphite::get_graphite_routes().with_state(state.clone());
+            let response = app
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get("x-has-more").is_some(),
+                has_more,
+                "{}",
+                uri
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let ids: Vec<Value> = body
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["id"].clone())
+                .collect();
+            assert_eq!(json!(ids), expected, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tags_autocomplete() {
+        let state = get_flags_test_state("https://a.b");
+
+        for (uri, expected) in [
+            (
+                "/tags/autoComplete/tags",
+                json!(["category", "environment", "name", "service"]),
+            ),
+            ("/tags/autoComplete/tags?tagPrefix=n", json!(["name"])),
+            ("/tags/autoComplete/values?tag=name", json!(["health"])),
+            (
+                "/tags/autoComplete/tags?tagPrefix=e",
+                json!(["environment"]),
+            ),
+            ("/tags/autoComplete/tags?limit=1", json!(["category"])),
+            (
+                "/tags/autoComplete/values?tag=environment",
+                json!(["env1", "env2"]),
+            ),
+            (
+                "/tags/autoComplete/values?tag=environment&valuePrefix=env2",
+                json!(["env2"]),
+            ),
+            ("/tags/autoComplete/values?tag=service", json!(["srvA"])),
+            (
+                "/tags/autoComplete/values?tag=service&valuePrefix=x",
+                json!([]),
+            ),
+            ("/tags/autoComplete/values?tag=category", json!(["compute"])),
+            ("/tags/autoComplete/values?tag=unknown", json!([])),
+        ] {
+            let app = graphite::get_graphite_routes().with_state(state.clone());
+            let response = app
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, expected, "{}", uri);
+        }
+    }
+
+    async fn get_functions(state: types::SharedState) -> serde_json::Value {
+        let app = graphite::get_graphite_routes().with_state(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/functions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_functions() {
+        let functions = get_functions(get_flags_test_state("http://localhost:1")).await;
+        for name in [
+            "alias",
+            "aliasByNode",
+            "sumSeries",
+            "asPercent",
+            "smartSummarize",
+        ] {
+            assert_eq!(functions[name]["name"], json!(name));
+            assert!(!functions[name]["params"].as_array().unwrap().is_empty());
+        }
+        assert_eq!(
+            functions["alias"]["function"],
+            json!("alias(seriesList, newName)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_functions_proxy() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/functions")
+            .with_body(json!({"movingAverage": {"name": "movingAverage"}}).to_string())
+            .create_async()
+            .await;
+        let state = get_flags_test_state(server.url().as_str());
+        state.write().await.config.datasource.proxy_functions = true;
+
+        let functions = get_functions(state.clone()).await;
+        mock.assert_async().await;
+        assert_eq!(
+            functions,
+            json!({"movingAverage": {"name": "movingAverage"}})
         );
+
+        // Datasource failures fall back to the built-in list
+        state.write().await.config.datasource.url = "http://localhost:1".to_string();
+        let functions = get_functions(state).await;
+        assert_eq!(functions["alias"]["name"], json!("alias"));
+    }
+
+    #[tokio::test]
+    async fn test_render_datasource_error() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_status(500)
+            .create_async()
+            .await;
+        let state = get_flags_test_state(server.url().as_str());
+
+        for uri in [
+            "/render?target=flag.env1.srvA.metric-1&from=-5min&until=-2min",
+            "/render?target=health.env1.srvA&from=-5min&until=-2min",
+            "/render?target=health.env1.*&from=-5min&until=-2min",
+        ] {
+            let app = graphite::get_graphite_routes().with_state(state.clone());
+            let response = app
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY, "{}", uri);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, json!({"message": "Error reading data from TSDB"}));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_graphite_data_no_null_points() {
+        let mut server = mockito::Server::new_async().await;
+        let req_client = ClientBuilder::new().build().unwrap();
+        let mut targets: HashMap<String, String> = HashMap::new();
+        targets.insert("alias".to_string(), "query".to_string());
+        let url = server.url();
+        let plain = graphite::QueryOptions::default();
+        let no_nulls = graphite::QueryOptions {
+            no_null_points: true,
+            ..Default::default()
+        };
+        let fetch = graphite::FetchContext::new(&req_client, url.as_str());
+        let range = graphite::TimeRange::parse("-5min", "-2min");
+        let get_data = |no_null_points: bool| {
+            let options = if no_null_points { &no_nulls } else { &plain };
+            graphite::get_graphite_data(&fetch, &targets, &range, 15, options)
+        };
+        // Requests carrying noNullPoints fail, all others succeed
+        let with_param = server
+            .mock("GET", "/render")
+            .match_query(Matcher::UrlEncoded("noNullPoints".into(), "true".into()))
+            .with_status(500)
+            .create_async()
+            .await;
+        let without_param = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body("[]")
+            .create_async()
+            .await;
+
+        assert!(get_data(false).await.is_ok());
+        without_param.assert_async().await;
+        assert!(get_data(true).await.is_err());
+        with_param.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_get_graphite_data_timestamp_alignment() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!([
+                    {"target": "a", "datapoints": [[1.0, 600], [2.0, 900], [null, 1200]]},
+                    {"target": "b", "datapoints": [[1.0, 600]]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let targets = HashMap::from([
+            ("a".to_string(), "q1".to_string()),
+            ("b".to_string(), "q2".to_string()),
+        ]);
+        let fetch = |alignment| {
+            let options = graphite::QueryOptions {
+                timestamp_alignment: alignment,
+                ..Default::default()
+            };
+            let url = server.url();
+            let targets = targets.clone();
+            async move {
+                graphite::get_graphite_data(
+                    &graphite::FetchContext::new(
+                        &ClientBuilder::new().build().unwrap(),
+                        url.as_str(),
+                    ),
+                    &targets,
+                    &graphite::TimeRange::default(),
+                    10,
+                    &options,
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        let res = fetch(config::TimestampAlignment::Start).await;
+        assert_eq!(
+            res[0].datapoints,
+            vec![(Some(1.0), 600), (Some(2.0), 900), (None, 1200)]
+        );
+        let res = fetch(config::TimestampAlignment::End).await;
+        assert_eq!(
+            res[0].datapoints,
+            vec![(Some(1.0), 900), (Some(2.0), 1200), (None, 1500)]
+        );
+        // Width is shared by all series of the response
+        assert_eq!(res[1].datapoints, vec![(Some(1.0), 900)]);
+    }
+
+    #[test]
+    fn test_bucket_width() {
+        let series = |datapoints: Vec<(Option<f32>, u32)>| graphite::GraphiteData {
+            target: "a".to_string(),
+            datapoints,
+        };
+        let range =
+            graphite::TimeRange::parse("2022-01-01T00:00:00+00:00", "2022-01-01T01:00:00+00:00");
+        assert_eq!(
+            graphite::bucket_width(
+                &[
+                    series(vec![(Some(1.0), 600)]),
+                    series(vec![(Some(1.0), 600), (None, 1200), (None, 1500)]),
+                ],
+                &range,
+                10
+            ),
+            Some(300)
+        );
+        // Derived from the requested range when no series has two datapoints
+        assert_eq!(
+            graphite::bucket_width(&[series(vec![(Some(1.0), 600)])], &range, 7),
+            Some(515)
+        );
+        assert_eq!(
+            graphite::bucket_width(
+                &[series(vec![(Some(1.0), 600)])],
+                &graphite::TimeRange::parse("-1h", "now"),
+                10
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_graphite_data_tz() {
+        let req_client = ClientBuilder::new().build().unwrap();
+        let mut targets: HashMap<String, String> = HashMap::new();
+        targets.insert("alias".to_string(), "query".to_string());
+        let from = DateTime::parse_from_rfc3339("2022-01-01T00:00:00+01:00").ok();
+        let to = DateTime::parse_from_rfc3339("2022-01-31T22:30:00+00:00").ok();
+
+        for (tz, date_format, expected_from, expected_until) in [
+            (0, "%H:%M_%Y%m%d", "23:00_20211231", "22:30_20220131"),
+            (2 * 3600, "%H:%M_%Y%m%d", "01:00_20220101", "00:30_20220201"),
+            (
+                -(5 * 3600 + 1800),
+                "%H:%M_%Y%m%d",
+                "17:30_20211231",
+                "17:00_20220131",
+            ),
+            (
+                3600,
+                "%Y-%m-%dT%H:%M",
+                "2022-01-01T00:00",
+                "2022-01-31T23:30",
+            ),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let mock = server
+                .mock("GET", "/render")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("from".into(), expected_from.into()),
+                    Matcher::UrlEncoded("until".into(), expected_until.into()),
+                ]))
+                .with_body("[]")
+                .create_async()
+                .await;
+            let options = graphite::QueryOptions {
+                date_format: date_format.to_string(),
+                tz_offset: FixedOffset::east_opt(tz).unwrap(),
+                ..Default::default()
+            };
+            let res = graphite::get_graphite_data(
+                &graphite::FetchContext::new(&req_client, server.url().as_str()),
+                &targets,
+                &graphite::TimeRange {
+                    from: from.map(graphite::TimeBound::Time),
+                    until: to.map(graphite::TimeBound::Time),
+                },
+                15,
+                &options,
+            )
+            .await;
+            assert!(res.is_ok(), "offset {}", tz);
+            mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_graphite_data_errors() {
+        let req_client = ClientBuilder::new().build().unwrap();
+        let mut targets: HashMap<String, String> = HashMap::new();
+        targets.insert("alias".to_string(), "query".to_string());
+
+        for (status, body, expected_kind, expected_message) in [
+            (
+                400,
+                "Bad target",
+                types::DatasourceErrorKind::Status,
+                "datasource request failed (status 400)",
+            ),
+            (
+                200,
+                "not json",
+                types::DatasourceErrorKind::InvalidResponse,
+                "invalid datasource response",
+            ),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let _mock = server
+                .mock("GET", "/render")
+                .match_query(Matcher::Any)
+                .with_status(status)
+                .with_body(body)
+                .create_async()
+                .await;
+            let err = graphite::get_graphite_data(
+                &graphite::FetchContext::new(&req_client, server.url().as_str()),
+                &targets,
+                &graphite::TimeRange::parse("-5min", "-2min"),
+                15,
+                &graphite::QueryOptions::default(),
+            )
+            .await
+            .unwrap_err();
+            match err {
+                types::CloudMonError::GraphiteError {
+                    kind,
+                    status: err_status,
+                    message,
+                    targets,
+                } => {
+                    assert_eq!(kind, expected_kind);
+                    assert_eq!(err_status, Some(status as u16));
+                    assert_eq!(targets, vec!["alias(query,'alias')".to_string()]);
+                    // Upstream details are only logged
+                    assert_eq!(message, expected_message);
+                    if kind == types::DatasourceErrorKind::Status {
+                        assert!(logs_contain("[400 Bad Request] Bad target"));
+                    } else {
+                        assert!(logs_contain("invalid response: "));
+                    }
+                }
+                other => panic!("unexpected error {:?}", other),
+            }
+        }
     }
 }
@@ -6,4 +6,10 @@ pub mod api;
 pub mod common;
 pub mod config;
 pub mod graphite;
+pub mod logging;
+pub mod reporter;
+pub mod sd;
+pub mod telemetry;
+#[cfg(test)]
+pub(crate) mod test_utils;
 pub mod types;
@@ -0,0 +1,50 @@
+//! Logging setup
+//!
+//! Initialization of the tracing subscriber shared by the binaries
+//!
+use tracing::Subscriber;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::config::{Config, LogFormat};
+
+/// Determine log format: `CLOUDMON_LOG_FORMAT` environment variable (`text` or `json`) takes
+/// precedence over the `log_format` from the configuration.
+pub fn get_log_format(config: Option<&Config>) -> LogFormat {
+    match std::env::var("CLOUDMON_LOG_FORMAT")
+        .map(|x| x.to_lowercase())
+        .as_deref()
+    {
+        Ok("json") => LogFormat::Json,
+        Ok("text") => LogFormat::Text,
+        _ => config.map(|x| x.log_format.clone()).unwrap_or_default(),
+    }
+}
+
+/// Build subscriber writing logs in the requested format filtered by `RUST_LOG`
+pub fn build_subscriber(format: &LogFormat) -> Box<dyn Subscriber + Send + Sync> {
+    let filter = EnvFilter::new(std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()));
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => Box::new(registry.with(tracing_subscriber::fmt::layer())),
+        LogFormat::Json => Box::new(registry.with(tracing_subscriber::fmt::layer().json())),
+    }
+}
+
+/// Install global subscriber
+pub fn init_logging(format: &LogFormat) {
+    build_subscriber(format).init();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::LogFormat;
+    use crate::logging;
+
+    #[test]
+    fn test_json_subscriber() {
+        let subscriber = logging::build_subscriber(&LogFormat::Json);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(environment = "env1", service = "srvA", "Test event");
+        });
+    }
+}
@@ -0,0 +1,1861 @@
+//! Status Dashboard reporter logic
+//!
+//! Helpers used by the `cloudmon-metrics-reporter` binary to decide what to report.
+//!
+use crate::api::v1::{HealthQuery, ServiceHealthResponse, ServiceHealthResult};
+use crate::config::{
+    Config, ImpactAggregation, ImpactMapping, MaintenanceWindow, ReporterConfig, StatusDashboardApi,
+};
+use crate::sd::{
+    self, Component, ComponentAttribute, ComponentIdCache, IncidentData, StatusDashboardComponent,
+};
+use crate::types::{CloudMonError, ServiceHealthData, ServiceHealthPoint};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use reqwest::{header::HeaderMap, ClientBuilder, StatusCode};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Incidents reported so far
+#[derive(Debug, Default)]
+pub struct ReportState {
+    /// Time during which the same impact is not reported again
+    pub cooldown: Duration,
+    /// component => (impact, time of the report)
+    reported: HashMap<String, (u8, Instant)>,
+}
+
+impl ReportState {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Whether an incident has been reported for the component and not resolved yet
+    pub fn is_open(&self, component: &str) -> bool {
+        self.reported.contains_key(component)
+    }
+
+    /// Forget the reported incident of the component once it is resolved
+    pub fn mark_resolved(&mut self, component: &str) {
+        self.reported.remove(component);
+    }
+}
+
+/// Decide whether the impact of the component should be reported and remember the report.
+///
+/// Impact is reported unless the same impact has already been reported within the cooldown.
+/// Healthy state (impact 0) is never reported and resets the state of the component.
+pub fn should_report(state: &mut ReportState, component: &str, impact: u8, now: Instant) -> bool {
+    if impact == 0 {
+        state.reported.remove(component);
+        return false;
+    }
+    if let Some((last_impact, last_time)) = state.reported.get(component) {
+        if *last_impact == impact && now.saturating_duration_since(*last_time) < state.cooldown {
+            return false;
+        }
+    }
+    state.reported.insert(component.to_string(), (impact, now));
+    true
+}
+
+/// Whether the service is covered by any of the maintenance windows at the given time
+pub fn is_in_maintenance(windows: &[MaintenanceWindow], service: &str, at: DateTime<Utc>) -> bool {
+    windows.iter().any(|window| {
+        window.from <= at
+            && at < window.to
+            && window
+                .services
+                .as_ref()
+                .is_none_or(|services| services.iter().any(|x| x == service))
+    })
+}
+
+/// Translate the health weight into the Status Dashboard impact. Weights missing in the map are
+/// passed through unchanged.
+pub fn map_impact(impact_map: &[ImpactMapping], weight: u8) -> u8 {
+    impact_map
+        .iter()
+        .find(|x| x.weight == weight)
+        .map_or(weight, |x| x.impact)
+}
+
+/// Fetch health of the service in the environment from the convertor
+pub async fn fetch_service_health(
+    client: &reqwest::Client,
+    convertor_url: &str,
+    api_token: Option<&str>,
+    environment: &str,
+    service: &str,
+) -> Result<ServiceHealthResponse, CloudMonError> {
+    // Query env/service for time [-5min..-2min]
+    let mut req = client
+        .get(format!("{}/api/v1/health", convertor_url))
+        .query(&[
+            ("environment", environment),
+            ("service", service),
+            ("from", "-5min"),
+            ("to", "-2min"),
+        ]);
+    if let Some(token) = api_token {
+        req = req.bearer_auth(token);
+    }
+    let rsp = req
+        .send()
+        .await
+        .map_err(|e| CloudMonError::ConvertorError(e.to_string()))?;
+    if !rsp.status().is_success() {
+        return Err(CloudMonError::ConvertorError(format!(
+            "[{}] {}",
+            rsp.status(),
+            rsp.text().await.unwrap_or_default()
+        )));
+    }
+    rsp.json::<ServiceHealthResponse>()
+        .await
+        .map_err(|e| CloudMonError::ConvertorError(format!("Cannot process response: {}", e)))
+}
+
+/// Fetch health of all (environment, service) pairs with a single /health/batch request.
+///
+/// Results are returned in the order of the checks. `None` is returned when the convertor does
+/// not support batch requests or fails to process the batch (5xx), the checks are then to be
+/// queried one by one.
+pub async fn fetch_services_health_batch(
+    client: &reqwest::Client,
+    convertor_url: &str,
+    api_token: Option<&str>,
+    checks: &[(String, String)],
+) -> Result<Option<Vec<Result<ServiceHealthResponse, CloudMonError>>>, CloudMonError> {
+    // Query env/service for time [-5min..-2min]
+    let queries: Vec<HealthQuery> = checks
+        .iter()
+        .map(|(environment, service)| HealthQuery {
+            from: "-5min".to_string(),
+            to: "-2min".to_string(),
+            max_data_points: 100,
+            service: service.clone(),
+            environment: environment.clone(),
+            timeout_secs: None,
+            rollup: None,
+            resolution_secs: None,
+        })
+        .collect();
+    let mut req = client
+        .post(format!("{}/api/v1/health/batch", convertor_url))
+        .json(&queries);
+    if let Some(token) = api_token {
+        req = req.bearer_auth(token);
+    }
+    let rsp = req
+        .send()
+        .await
+        .map_err(|e| CloudMonError::ConvertorError(e.to_string()))?;
+    if matches!(
+        rsp.status(),
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+    ) || rsp.status().is_server_error()
+    {
+        tracing::debug!("Batch request rejected with {}", rsp.status());
+        return Ok(None);
+    }
+    if !rsp.status().is_success() {
+        return Err(CloudMonError::ConvertorError(format!(
+            "[{}] {}",
+            rsp.status(),
+            rsp.text().await.unwrap_or_default()
+        )));
+    }
+    let results = rsp
+        .json::<Vec<ServiceHealthResult>>()
+        .await
+        .map_err(|e| CloudMonError::ConvertorError(format!("Cannot process response: {}", e)))?;
+    if results.len() != checks.len() {
+        return Err(CloudMonError::ConvertorError(format!(
+            "Expected {} batch results, got {}",
+            checks.len(),
+            results.len()
+        )));
+    }
+    Ok(Some(
+        results
+            .into_iter()
+            .map(|res| match res {
+                ServiceHealthResult::Ok(rsp) => Ok(rsp),
+                ServiceHealthResult::Err { message, .. } => {
+                    Err(CloudMonError::ConvertorError(message))
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// Fetch health of all (environment, service) pairs.
+///
+/// Checks are sent as /health/batch requests of at most `concurrency` items. When a batch
+/// can not be processed the remaining pairs are queried one by one running at most
+/// `concurrency` requests at a time. Results are returned together with the checked pair.
+pub async fn check_services(
+    client: &reqwest::Client,
+    convertor_url: &str,
+    api_token: Option<&str>,
+    checks: Vec<(String, String)>,
+    concurrency: usize,
+) -> Vec<(String, String, Result<ServiceHealthResponse, CloudMonError>)> {
+    let concurrency = concurrency.max(1);
+    let mut results = Vec::with_capacity(checks.len());
+    let mut chunks = checks.chunks(concurrency);
+    let mut fallback: Vec<(String, String)> = Vec::new();
+    for chunk in chunks.by_ref() {
+        match fetch_services_health_batch(client, convertor_url, api_token, chunk).await {
+            Ok(Some(chunk_results)) => {
+                results.extend(
+                    chunk
+                        .iter()
+                        .cloned()
+                        .zip(chunk_results)
+                        .map(|((environment, service), res)| (environment, service, res)),
+                );
+            }
+            Ok(None) => {
+                tracing::debug!("Batch request not processed, querying one by one");
+                fallback.extend_from_slice(chunk);
+                break;
+            }
+            Err(e) => {
+                let message = match e {
+                    CloudMonError::ConvertorError(msg) => msg,
+                    e => e.to_string(),
+                };
+                results.extend(chunk.iter().cloned().map(|(environment, service)| {
+                    (
+                        environment,
+                        service,
+                        Err(CloudMonError::ConvertorError(message.clone())),
+                    )
+                }));
+            }
+        }
+    }
+    fallback.extend(chunks.flatten().cloned());
+    let fallback_results: Vec<_> = stream::iter(fallback)
+        .map(|(environment, service)| async move {
+            let res =
+                fetch_service_health(client, convertor_url, api_token, &environment, &service)
+                    .await;
+            (environment, service, res)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    results.extend(fallback_results);
+    results
+}
+
+/// Delay before the retry following the failed `attempt` (counted from 0): `base * 2^attempt`
+/// capped at `max`, of which up to one half is randomized by `jitter` (`0.0..=1.0`).
+pub fn backoff_delay(attempt: u32, base: Duration, max: Duration, jitter: f64) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Pseudo random jitter in `0.0..1.0`
+fn jitter() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.subsec_nanos() as f64 / 1_000_000_000.0)
+        .unwrap_or(0.5)
+}
+
+/// Fetch Status Dashboard components retrying failures with exponential backoff
+pub async fn fetch_components_with_retry(
+    client: &reqwest::Client,
+    url: &str,
+    components_path: &str,
+    headers: &HeaderMap,
+    config: &ReporterConfig,
+) -> Result<Vec<StatusDashboardComponent>, CloudMonError> {
+    let attempts = config.component_fetch_attempts.max(1);
+    let mut attempt = 0;
+    loop {
+        match sd::fetch_components(client, url, components_path, headers).await {
+            Ok(components) => return Ok(components),
+            Err(e) if attempt + 1 < attempts => {
+                let delay = backoff_delay(
+                    attempt,
+                    Duration::from_secs(config.component_fetch_backoff_secs),
+                    Duration::from_secs(config.component_fetch_backoff_max_secs),
+                    jitter(),
+                );
+                tracing::warn!(
+                    "Cannot fetch Status Dashboard components ({}), retrying in {:?}",
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Aggregate health datapoints of the window into a single point to report.
+///
+/// Returned point is the latest datapoint with the aggregated impact, so that its triggered
+/// metrics describe the reported impact. `None` is returned for an empty window.
+pub fn aggregate_impact(
+    metrics: &ServiceHealthData,
+    aggregation: &ImpactAggregation,
+) -> Option<ServiceHealthPoint> {
+    let last = metrics.last()?;
+    let impact = match aggregation {
+        ImpactAggregation::Last => last.value,
+        ImpactAggregation::Max => metrics.iter().map(|x| x.value).max().unwrap_or_default(),
+        ImpactAggregation::Majority => {
+            let mut values: Vec<u8> = metrics.iter().map(|x| x.value).collect();
+            values.sort_unstable_by(|a, b| b.cmp(a));
+            // value at the middle of the descending list is reached by at least half of points
+            values[(values.len() - 1) / 2]
+        }
+    };
+    let point = metrics
+        .iter()
+        .rev()
+        .find(|x| x.value == impact)
+        .unwrap_or(last)
+        .clone();
+    Some(point)
+}
+
+/// Reporter context kept between the evaluation cycles
+pub struct Reporter {
+    pub config: Config,
+    /// Base URL of the convertor API
+    pub convertor_url: String,
+    /// Only log what would be reported without calling the Status Dashboard
+    pub dry_run: bool,
+    pub state: ReportState,
+    client: reqwest::Client,
+    /// environment => service => component
+    components: HashMap<String, HashMap<String, Component>>,
+    /// Ids of the components registered in the Status Dashboard
+    component_id_cache: ComponentIdCache,
+    /// When the component cache was last rebuilt
+    component_cache_updated: Option<Instant>,
+    /// `env/service` => id of the open incident created with the v2 API
+    incident_ids: HashMap<String, u32>,
+    /// Services (`env/service`) and weights already warned about exceeding the `max_impact`
+    clamped_impacts: HashSet<(String, u8)>,
+    /// Last cycle getting any health from the convertor (start of the reporter initially)
+    last_success: Instant,
+}
+
+impl Reporter {
+    pub fn new(config: Config) -> Self {
+        let mut components: HashMap<String, HashMap<String, Component>> = HashMap::new();
+        for env in config.environments.iter() {
+            let comp_env_entry = components.entry(env.name.clone()).or_default();
+            let mut env_attrs: Vec<ComponentAttribute> = Vec::new();
+            if let Some(ref attrs) = env.attributes {
+                for (key, val) in attrs.iter() {
+                    env_attrs.push(ComponentAttribute {
+                        name: key.to_string(),
+                        value: val.clone(),
+                    });
+                }
+            }
+
+            for component in config.health_metrics.iter() {
+                let name = match component.1.component_name {
+                    Some(ref name) => name.clone(),
+                    None if !config.reporter.require_component_name => {
+                        tracing::info!(
+                            "No component_name is given for {}, using the service name",
+                            component.0
+                        );
+                        component.0.clone()
+                    }
+                    None => {
+                        tracing::warn!("No component_name is given for {}", component.1.service);
+                        continue;
+                    }
+                };
+                comp_env_entry.insert(
+                    component.0.clone(),
+                    Component {
+                        name,
+                        attributes: env_attrs.clone(),
+                    },
+                );
+            }
+        }
+        Self {
+            convertor_url: match config.reporter.convertor_url {
+                Some(ref url) => url.trim_end_matches('/').to_string(),
+                None => format!("http://localhost:{}", config.server.port),
+            },
+            dry_run: false,
+            state: ReportState::new(Duration::from_secs(config.reporter.incident_cooldown_secs)),
+            client: ClientBuilder::new()
+                .timeout(Duration::from_secs(2))
+                .build()
+                .unwrap(),
+            components,
+            component_id_cache: ComponentIdCache::new(),
+            component_cache_updated: None,
+            incident_ids: HashMap::new(),
+            clamped_impacts: HashSet::new(),
+            last_success: Instant::now(),
+            config,
+        }
+    }
+
+    /// Is the component cache empty or older than `reporter.component_cache_ttl_secs`?
+    fn component_cache_expired(&self, now: Instant) -> bool {
+        let ttl = Duration::from_secs(self.config.reporter.component_cache_ttl_secs);
+        match self.component_cache_updated {
+            Some(updated) if !self.component_id_cache.is_empty() => {
+                now.saturating_duration_since(updated) >= ttl
+            }
+            _ => true,
+        }
+    }
+
+    /// Seconds since the health was last fetched successfully from the convertor
+    pub fn seconds_since_last_success(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.last_success).as_secs()
+    }
+
+    /// Run single evaluation cycle: check health of all services in all environments and
+    /// report or resolve incidents of their components.
+    pub async fn run_cycle(&mut self) {
+        self.run_cycle_at(Instant::now(), Utc::now()).await
+    }
+
+    /// Run single evaluation cycle at the given time. `now` drives the report and cache
+    /// expiration, `now_utc` the maintenance windows and the incident end dates.
+    pub async fn run_cycle_at(&mut self, now: Instant, now_utc: DateTime<Utc>) {
+        let sdb_config = self
+            .config
+            .status_dashboard
+            .as_ref()
+            .expect("Status dashboard section is missing");
+        // Sign fresh token every cycle since it expires
+        let headers = match sd::build_auth_headers(sdb_config) {
+            Ok(headers) => headers,
+            Err(e) => {
+                tracing::error!("Cannot build Status Dashboard authorization: {}", e);
+                return;
+            }
+        };
+        let api_v2 = sdb_config.api_version == StatusDashboardApi::V2;
+        if api_v2 && self.component_cache_expired(now) {
+            match fetch_components_with_retry(
+                &self.client,
+                sdb_config.url.as_str(),
+                sdb_config.components_path.as_str(),
+                &headers,
+                &self.config.reporter,
+            )
+            .await
+            {
+                Ok(components) => {
+                    self.component_id_cache = sd::build_component_id_cache(components);
+                    self.component_cache_updated = Some(now);
+                }
+                // Keep reporting with the previous (possibly empty) cache, unknown components are
+                // retried in the next cycle
+                Err(e) => {
+                    tracing::error!("Cannot fetch Status Dashboard components: {}", e);
+                }
+            }
+        }
+        let checks: Vec<(String, String)> = self
+            .config
+            .environments
+            .iter()
+            .flat_map(|env| {
+                self.config
+                    .health_metrics
+                    .keys()
+                    .map(|service| (env.name.clone(), service.clone()))
+            })
+            .collect();
+        // Query metric-convertor for the status of every env and component (health_metric
+        // service)
+        let results = check_services(
+            &self.client,
+            self.convertor_url.as_str(),
+            self.config.reporter.api_token.as_deref(),
+            checks,
+            self.config.reporter.concurrency,
+        )
+        .await;
+        if results.iter().any(|(_, _, res)| res.is_ok()) {
+            self.last_success = now;
+        } else if !results.is_empty() {
+            tracing::warn!(
+                seconds_since_last_success = self.seconds_since_last_success(now),
+                "No health could be fetched from the convertor"
+            );
+        }
+        // Incidents to create (v2 API) with the reported `env/service` of their components,
+        // components sharing the same impact, start and text are reported together
+        let mut incidents: Vec<(IncidentData, Vec<(String, u32)>)> = Vec::new();
+        for (env_name, service, result) in results {
+            let data = match result {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::error!("Cannot get health of {} in {}: {}", service, env_name, e);
+                    continue;
+                }
+            };
+            tracing::debug!("response {:?}", data);
+            // Aggregate metrics of the window into single impact
+            let last =
+                match aggregate_impact(&data.metrics, &self.config.reporter.impact_aggregation) {
+                    Some(last) => last,
+                    None => continue,
+                };
+            let component = match self.components.get(&env_name).and_then(|x| x.get(&service)) {
+                Some(component) => component,
+                None => continue,
+            };
+            let report_key = format!("{}/{}", env_name, service);
+            // Has the service recovered from reported issues?
+            if last.value == 0 && self.state.is_open(&report_key) {
+                // Both the command line flag and the Status Dashboard setting skip resolving
+                let dry_run = self.dry_run || sdb_config.dry_run;
+                tracing::info!(
+                    environment = env_name.as_str(),
+                    service = service.as_str(),
+                    component_name = component.name.as_str(),
+                    dry_run,
+                    "Service recovered, resolving incident"
+                );
+                if dry_run {
+                    self.incident_ids.remove(&report_key);
+                    self.state.mark_resolved(&report_key);
+                    continue;
+                }
+                if !api_v2 {
+                    match sd::resolve_incident(
+                        &self.client,
+                        sdb_config.url.as_str(),
+                        sdb_config.component_status_path.as_str(),
+                        &headers,
+                        component,
+                    )
+                    .await
+                    {
+                        Ok(()) => self.state.mark_resolved(&report_key),
+                        Err(e) => {
+                            tracing::error!("Error during resolving incident: {}", e);
+                        }
+                    }
+                    continue;
+                }
+                let incident_id = match self.incident_ids.get(&report_key) {
+                    Some(id) => *id,
+                    None => {
+                        tracing::warn!(
+                            environment = env_name.as_str(),
+                            service = service.as_str(),
+                            "Id of the incident is not known, it is not resolved"
+                        );
+                        self.state.mark_resolved(&report_key);
+                        continue;
+                    }
+                };
+                // Event is resolved once all of its components recovered
+                let shared = self
+                    .incident_ids
+                    .iter()
+                    .any(|(key, id)| *id == incident_id && *key != report_key);
+                let res = match shared {
+                    true => Ok(()),
+                    false => {
+                        sd::resolve_event(
+                            &self.client,
+                            sdb_config.url.as_str(),
+                            sdb_config.events_path.as_str(),
+                            &headers,
+                            incident_id,
+                            now_utc,
+                        )
+                        .await
+                    }
+                };
+                match res {
+                    Ok(()) => {
+                        self.incident_ids.remove(&report_key);
+                        self.state.mark_resolved(&report_key);
+                    }
+                    Err(e) => {
+                        tracing::error!("Error during resolving incident {}: {}", incident_id, e);
+                    }
+                }
+            }
+            // Is metric showing issues and not yet reported?
+            else if last.value > 0 {
+                if is_in_maintenance(
+                    &self.config.reporter.maintenance_windows,
+                    service.as_str(),
+                    now_utc,
+                ) {
+                    tracing::info!(
+                        environment = env_name.as_str(),
+                        service = service.as_str(),
+                        impact = last.value,
+                        "Service is in maintenance, incident is suppressed"
+                    );
+                    continue;
+                }
+                let component_id = match api_v2 {
+                    false => None,
+                    true => match sd::find_component_id(
+                        &self.component_id_cache,
+                        component,
+                        self.config.reporter.normalize_component_names,
+                    ) {
+                        Some(id) => Some(id),
+                        None => {
+                            // Not marked as reported, the incident is retried with the
+                            // refreshed cache in the next cycle
+                            tracing::error!(
+                                "Component {} {:?} is not known to the Status Dashboard",
+                                component.name,
+                                component.attributes
+                            );
+                            self.component_cache_updated = None;
+                            continue;
+                        }
+                    },
+                };
+                let mapped = map_impact(&self.config.reporter.impact_map, last.value);
+                let impact = mapped.min(sdb_config.max_impact);
+                if impact != mapped
+                    && self
+                        .clamped_impacts
+                        .insert((report_key.clone(), last.value))
+                {
+                    tracing::warn!(
+                        environment = env_name.as_str(),
+                        service = service.as_str(),
+                        "Weight {} exceeds the Status Dashboard max_impact, reporting impact {}",
+                        last.value,
+                        impact
+                    );
+                }
+                if !should_report(&mut self.state, report_key.as_str(), impact, now) {
+                    continue;
+                }
+                tracing::info!(
+                    environment = env_name.as_str(),
+                    service = service.as_str(),
+                    component_name = component.name.as_str(),
+                    component_id,
+                    impact,
+                    matched_expression = last.matched_expression.as_deref(),
+                    triggered_metrics = ?last.triggered,
+                    dry_run = self.dry_run,
+                    "Bad status found, reporting component"
+                );
+                if self.dry_run {
+                    continue;
+                }
+                let component_id = match component_id {
+                    Some(id) => id,
+                    None => {
+                        if let Err(e) = sd::report_component_status_with_auth_retry(
+                            &self.client,
+                            sdb_config,
+                            &headers,
+                            component,
+                            impact,
+                        )
+                        .await
+                        {
+                            tracing::error!("Error during reporting component status: {}", e);
+                            // Report again in the next cycle
+                            self.state.mark_resolved(&report_key);
+                        }
+                        continue;
+                    }
+                };
+                let render = |template: &str| {
+                    sd::render_incident_text(template, &service, &env_name, impact)
+                };
+                let incident = match sd::build_incident_data(
+                    component_id,
+                    impact,
+                    last.ts,
+                    render(&sdb_config.incident_title),
+                    render(&sdb_config.incident_description),
+                ) {
+                    Ok(incident) => incident,
+                    Err(e) => {
+                        tracing::error!("Cannot build incident of {}: {}", report_key, e);
+                        self.state.mark_resolved(&report_key);
+                        continue;
+                    }
+                };
+                match incidents.iter_mut().find(|(x, _)| {
+                    x.impact == incident.impact
+                        && x.start_date == incident.start_date
+                        && x.title == incident.title
+                        && x.description == incident.description
+                }) {
+                    Some((existing, keys)) => {
+                        if !existing.components.contains(&component_id) {
+                            existing.components.push(component_id);
+                        }
+                        keys.push((report_key, component_id));
+                    }
+                    None => incidents.push((incident, vec![(report_key, component_id)])),
+                }
+            }
+        }
+        // Events replaced by the new ones (i.e. after the impact changed)
+        let mut superseded: Vec<u32> = Vec::new();
+        for (incident, keys) in incidents.iter_mut() {
+            incident.components.sort_unstable();
+            match sd::create_incident_with_auth_retry(&self.client, sdb_config, &headers, incident)
+                .await
+            {
+                Ok(results) => {
+                    for res in results {
+                        let res_keys = keys.iter().filter(|(_, id)| *id == res.component_id);
+                        match res.error {
+                            Some(error) => {
+                                tracing::error!(
+                                    component_id = res.component_id,
+                                    "Status Dashboard refused incident: {}",
+                                    error
+                                );
+                                for (key, _) in res_keys {
+                                    self.state.mark_resolved(key);
+                                }
+                            }
+                            None => {
+                                tracing::info!(
+                                    component_id = res.component_id,
+                                    incident_id = res.incident_id,
+                                    "Incident created"
+                                );
+                                if let Some(incident_id) = res.incident_id {
+                                    for (key, _) in res_keys {
+                                        if let Some(previous) =
+                                            self.incident_ids.insert(key.clone(), incident_id)
+                                        {
+                                            if previous != incident_id {
+                                                superseded.push(previous);
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Error during creating incident of components {:?}: {}",
+                        incident.components,
+                        e
+                    );
+                    // Report again in the next cycle
+                    for (key, _) in keys.iter() {
+                        self.state.mark_resolved(key);
+                    }
+                }
+            }
+        }
+        superseded.sort_unstable();
+        superseded.dedup();
+        for incident_id in superseded {
+            // Event is resolved once none of its components refers to it anymore
+            if self.incident_ids.values().any(|id| *id == incident_id) {
+                continue;
+            }
+            tracing::info!(incident_id, "Resolving incident replaced by a new one");
+            if let Err(e) = sd::resolve_event(
+                &self.client,
+                sdb_config.url.as_str(),
+                sdb_config.events_path.as_str(),
+                &headers,
+                incident_id,
+                now_utc,
+            )
+            .await
+            {
+                tracing::error!("Error during resolving incident {}: {}", incident_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::v1::ServiceHealthResponse;
+    use crate::config::{
+        Config, ImpactAggregation, ImpactMapping, MaintenanceWindow, ReporterConfig,
+        StatusDashboardApi,
+    };
+    use crate::reporter::{
+        aggregate_impact, backoff_delay, check_services, fetch_components_with_retry,
+        is_in_maintenance, map_impact, should_report, ReportState, Reporter,
+    };
+    use crate::types::ServiceHealthPoint;
+    use axum::{extract::Query, routing::get, Json, Router};
+    use chrono::{DateTime, Duration as ChronoDuration, Utc};
+    use reqwest::header::HeaderMap;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn get_response(values: &[u8]) -> ServiceHealthResponse {
+        ServiceHealthResponse {
+            name: "srvA".to_string(),
+            service_category: "compute".to_string(),
+            environment: "env1".to_string(),
+            metrics: values
+                .iter()
+                .enumerate()
+                .map(|(i, x)| ServiceHealthPoint {
+                    ts: 100 + i as u32 * 60,
+                    value: *x,
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_impact_last() {
+        let rsp = get_response(&[2, 2, 2, 0]);
+        let point = aggregate_impact(&rsp.metrics, &ImpactAggregation::Last).unwrap();
+        assert_eq!(point.value, 0);
+        assert_eq!(point.ts, 280);
+        assert!(aggregate_impact(&Vec::new(), &ImpactAggregation::Last).is_none());
+    }
+
+    #[test]
+    fn test_aggregate_impact_max() {
+        let rsp = get_response(&[0, 2, 1, 0]);
+        let point = aggregate_impact(&rsp.metrics, &ImpactAggregation::Max).unwrap();
+        assert_eq!(point.value, 2);
+        assert_eq!(point.ts, 160);
+        let rsp = get_response(&[0, 0]);
+        let point = aggregate_impact(&rsp.metrics, &ImpactAggregation::Max).unwrap();
+        assert_eq!(point.value, 0);
+    }
+
+    #[test]
+    fn test_aggregate_impact_majority() {
+        // Short spike at the end is ignored
+        let rsp = get_response(&[0, 0, 0, 2]);
+        let point = aggregate_impact(&rsp.metrics, &ImpactAggregation::Majority).unwrap();
+        assert_eq!(point.value, 0);
+        // Sustained degradation is reported even when the last point recovered
+        let rsp = get_response(&[1, 2, 1, 0]);
+        let point = aggregate_impact(&rsp.metrics, &ImpactAggregation::Majority).unwrap();
+        assert_eq!(point.value, 1);
+        assert_eq!(point.ts, 220);
+        // Mixed impacts count towards the lower one
+        let rsp = get_response(&[0, 2, 1, 0]);
+        let point = aggregate_impact(&rsp.metrics, &ImpactAggregation::Majority).unwrap();
+        assert_eq!(point.value, 1);
+        assert_eq!(point.ts, 220);
+    }
+
+    #[test]
+    fn test_should_report_cooldown() {
+        let mut state = ReportState::new(Duration::from_secs(300));
+        let now = Instant::now();
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+        assert!(!should_report(
+            &mut state,
+            "env1/srvA",
+            1,
+            now + Duration::from_secs(60)
+        ));
+        // Other components are not affected
+        assert!(should_report(&mut state, "env1/srvB", 1, now));
+        // Cooldown expired
+        assert!(should_report(
+            &mut state,
+            "env1/srvA",
+            1,
+            now + Duration::from_secs(300)
+        ));
+        // Disabled cooldown reports every time
+        let mut state = ReportState::new(Duration::ZERO);
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+    }
+
+    #[test]
+    fn test_is_in_maintenance() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let window = |from: i64, to: i64, services: Option<&[&str]>| MaintenanceWindow {
+            from: now + ChronoDuration::hours(from),
+            to: now + ChronoDuration::hours(to),
+            services: services.map(|x| x.iter().map(|s| s.to_string()).collect()),
+        };
+        // Window covering now
+        assert!(is_in_maintenance(&[window(-1, 1, None)], "srvA", now));
+        // Window in the past
+        assert!(!is_in_maintenance(&[window(-2, -1, None)], "srvA", now));
+        // End is exclusive
+        assert!(!is_in_maintenance(&[window(-1, 0, None)], "srvA", now));
+        // Only the listed services are affected
+        let windows = [window(-1, 1, Some(&["srvB"]))];
+        assert!(is_in_maintenance(&windows, "srvB", now));
+        assert!(!is_in_maintenance(&windows, "srvA", now));
+        assert!(!is_in_maintenance(&[], "srvA", now));
+    }
+
+    #[test]
+    fn test_map_impact() {
+        let impact_map = [
+            ImpactMapping {
+                weight: 1,
+                impact: 1,
+            },
+            ImpactMapping {
+                weight: 2,
+                impact: 5,
+            },
+        ];
+        assert_eq!(map_impact(&impact_map, 2), 5);
+        assert_eq!(map_impact(&impact_map, 1), 1);
+        // Unmapped weights pass through
+        assert_eq!(map_impact(&impact_map, 3), 3);
+        assert_eq!(map_impact(&[], 2), 2);
+    }
+
+    #[test]
+    fn test_should_report_impact_change() {
+        let mut state = ReportState::new(Duration::from_secs(300));
+        let now = Instant::now();
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+        assert!(should_report(&mut state, "env1/srvA", 2, now));
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+        assert!(state.is_open("env1/srvA"));
+        state.mark_resolved("env1/srvA");
+        assert!(!state.is_open("env1/srvA"));
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+        // Recovery resets the state
+        assert!(!should_report(&mut state, "env1/srvA", 0, now));
+        assert!(should_report(&mut state, "env1/srvA", 1, now));
+    }
+
+    #[tokio::test]
+    async fn test_check_services_concurrent() {
+        // Convertor answering every request after a delay
+        let app = Router::new().route(
+            "/api/v1/health",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                let mut rsp = get_response(&[0]);
+                rsp.name = query["service"].clone();
+                Json(rsp)
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let checks: Vec<(String, String)> = ["srvA", "srvB", "srvC", "srvD"]
+            .iter()
+            .map(|x| ("env1".to_string(), x.to_string()))
+            .collect();
+        let start = Instant::now();
+        let mut res = check_services(&reqwest::Client::new(), &url, None, checks, 4).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+        res.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(res.len(), 4);
+        for ((_, service, rsp), expected) in res.iter().zip(["srvA", "srvB", "srvC", "srvD"]) {
+            assert_eq!(service, expected);
+            assert_eq!(rsp.as_ref().unwrap().name, expected);
+        }
+    }
+
+    /// Configuration with the srvA service (Component A) in env1 (region Reg1) reporting to the
+    /// Status Dashboard at `url` (with an optional JWT secret)
+    fn get_reporter_config(url: &str, secret: Option<&str>) -> Config {
+        crate::config::Config::from_config_str(
+            format!(
+                "
+            datasource:
+              url: 'https://a.b'
+            server:
+              port: 3005
+            environments:
+              - name: env1
+                attributes:
+                  region: Reg1
+            flag_metrics: []
+            health_metrics:
+              srvA:
+                service: srvA
+                component_name: Component A
+                category: compute
+                metrics: []
+                expressions: []
+            status_dashboard:
+              url: '{}'
+              api_version: v2
+              {}
+            ",
+                url,
+                secret.map(|x| format!("secret: {}", x)).unwrap_or_default()
+            )
+            .as_str(),
+        )
+        .unwrap()
+    }
+
+    /// Add a service with the optional component name to the configuration
+    fn add_test_service(config: &mut Config, service: &str, component_name: Option<&str>) {
+        let mut def = config.health_metrics["srvA"].clone();
+        def.service = service.to_string();
+        def.component_name = component_name.map(|x| x.to_string());
+        config.health_metrics.insert(service.to_string(), def);
+    }
+
+    /// Reporter of the test configuration adjusted by `configure` using the convertor at `url`
+    fn get_test_reporter_with(
+        url: &str,
+        secret: Option<&str>,
+        configure: impl FnOnce(&mut Config),
+    ) -> Reporter {
+        let mut config = get_reporter_config(url, secret);
+        configure(&mut config);
+        let mut reporter = Reporter::new(config);
+        reporter.convertor_url = url.to_string();
+        reporter
+    }
+
+    fn get_test_reporter(url: &str, secret: Option<&str>) -> Reporter {
+        get_test_reporter_with(url, secret, |_| {})
+    }
+
+    /// Mock convertor reporting the impact for every service
+    async fn get_health_mock(server: &mut mockito::Server, impact: u8) -> mockito::Mock {
+        server
+            .mock("GET", "/api/v1/health")
+            .match_query(mockito::Matcher::Any)
+            .with_body(serde_json::to_string(&get_response(&[impact, impact])).unwrap())
+            .create_async()
+            .await
+    }
+
+    /// Mock Status Dashboard components with more attributes than in the config
+    async fn get_components_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v2/components")
+            .with_body(
+                serde_json::json!([
+                    {"id": 5, "name": "Component A", "attributes": [
+                        {"name": "category", "value": "compute"},
+                        {"name": "region", "value": "Reg1"}
+                    ]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_component_subset() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let components_mock = get_components_mock(&mut server).await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "components": [5],
+                "impact": 2,
+                "start_date": "1970-01-01T00:02:40Z",
+                "system": true
+            })))
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+
+        reporter.run_cycle().await;
+        components_mock.assert_async().await;
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_multiple_components() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let _components_mock = server
+            .mock("GET", "/v2/components")
+            .with_body(
+                serde_json::json!([
+                    {"id": 5, "name": "Component A", "attributes": [
+                        {"name": "region", "value": "Reg1"}
+                    ]},
+                    {"id": 7, "name": "Component B", "attributes": [
+                        {"name": "region", "value": "Reg1"}
+                    ]}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "components": [5, 7],
+                "impact": 2
+            })))
+            .expect(1)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter_with(server.url().as_str(), None, |config| {
+            add_test_service(config, "srvB", Some("Component B"))
+        });
+
+        reporter.run_cycle().await;
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_component_cache_ttl() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 0).await;
+        let components_mock = server
+            .mock("GET", "/v2/components")
+            .with_body(
+                serde_json::json!([{"id": 5, "name": "Component A", "attributes": [
+                    {"name": "region", "value": "Reg1"}
+                ]}])
+                .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        reporter.config.reporter.component_cache_ttl_secs = 60;
+        let start = Instant::now();
+
+        reporter.run_cycle_at(start, Utc::now()).await;
+        // Cache is still fresh
+        reporter
+            .run_cycle_at(start + Duration::from_secs(59), Utc::now())
+            .await;
+        // TTL elapsed, cache is rebuilt
+        reporter
+            .run_cycle_at(start + Duration::from_secs(60), Utc::now())
+            .await;
+        components_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_custom_paths() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let components_mock = server
+            .mock("GET", "/sd/components")
+            .with_body(
+                serde_json::json!([{"id": 5, "name": "Component A", "attributes": [
+                    {"name": "region", "value": "Reg1"}
+                ]}])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let sd_mock = server
+            .mock("POST", "/sd/incidents")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"components": [5]}),
+            ))
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let sdb_config = reporter.config.status_dashboard.as_mut().unwrap();
+        sdb_config.components_path = "/sd/components".to_string();
+        sdb_config.events_path = "/sd/incidents".to_string();
+
+        reporter.run_cycle().await;
+        components_mock.assert_async().await;
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_api_v1() {
+        let mut server = mockito::Server::new_async().await;
+        let convertor_mock = get_health_mock(&mut server, 2).await;
+        let components_mock = server
+            .mock("GET", "/v2/components")
+            .expect(0)
+            .create_async()
+            .await;
+        let report_mock = server
+            .mock("POST", "/api/v1/component_status")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "name": "Component A",
+                "impact": 2,
+                "attributes": [{"name": "region", "value": "Reg1"}]
+            })))
+            .create_async()
+            .await;
+        let resolve_mock = server
+            .mock("POST", "/api/v1/component_status")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"name": "Component A", "impact": 0}),
+            ))
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let sdb_config = reporter.config.status_dashboard.as_mut().unwrap();
+        sdb_config.api_version = StatusDashboardApi::V1;
+
+        reporter.run_cycle().await;
+        report_mock.assert_async().await;
+        assert!(reporter.state.is_open("env1/srvA"));
+
+        convertor_mock.remove_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 0).await;
+        reporter.run_cycle().await;
+        resolve_mock.assert_async().await;
+        assert!(!reporter.state.is_open("env1/srvA"));
+        components_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_api_v2_resolve() {
+        let mut server = mockito::Server::new_async().await;
+        let convertor_mock = get_health_mock(&mut server, 2).await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let create_mock = server
+            .mock("POST", "/v2/events")
+            .with_body(
+                serde_json::json!({"result": [{"component_id": 5, "incident_id": 12}]}).to_string(),
+            )
+            .create_async()
+            .await;
+        let resolve_mock = server
+            .mock("PATCH", "/v2/events/12")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"status": "resolved"}),
+            ))
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+
+        reporter.run_cycle().await;
+        create_mock.assert_async().await;
+
+        convertor_mock.remove_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 0).await;
+        reporter.run_cycle().await;
+        resolve_mock.assert_async().await;
+        assert!(!reporter.state.is_open("env1/srvA"));
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_api_v2_impact_change() {
+        let mut server = mockito::Server::new_async().await;
+        let convertor_mock = get_health_mock(&mut server, 2).await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let create_outage = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"impact": 2}),
+            ))
+            .with_body(
+                serde_json::json!({"result": [{"component_id": 5, "incident_id": 12}]}).to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let create_degraded = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"impact": 1}),
+            ))
+            .with_body(
+                serde_json::json!({"result": [{"component_id": 5, "incident_id": 13}]}).to_string(),
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let resolve_outage = server
+            .mock("PATCH", "/v2/events/12")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"status": "resolved"}),
+            ))
+            .expect(1)
+            .create_async()
+            .await;
+        let resolve_degraded = server
+            .mock("PATCH", "/v2/events/13")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"status": "resolved"}),
+            ))
+            .expect(1)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+
+        reporter.run_cycle().await;
+        create_outage.assert_async().await;
+
+        // New event replaces the previous one which gets resolved
+        convertor_mock.remove_async().await;
+        let convertor_mock = get_health_mock(&mut server, 1).await;
+        reporter.run_cycle().await;
+        create_degraded.assert_async().await;
+        resolve_outage.assert_async().await;
+
+        convertor_mock.remove_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 0).await;
+        reporter.run_cycle().await;
+        resolve_degraded.assert_async().await;
+        assert!(!reporter.state.is_open("env1/srvA"));
+        assert!(reporter.incident_ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_unknown_component() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let empty_components_mock = server
+            .mock("GET", "/v2/components")
+            .with_body("[]")
+            .create_async()
+            .await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"components": [5]}),
+            ))
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let start = Instant::now();
+
+        // Component is not registered yet, nothing is reported
+        reporter.run_cycle_at(start, Utc::now()).await;
+        empty_components_mock.assert_async().await;
+        assert!(!reporter.state.is_open("env1/srvA"));
+
+        // Cache is refreshed in the next cycle and the incident gets created
+        empty_components_mock.remove_async().await;
+        let components_mock = get_components_mock(&mut server).await;
+        reporter
+            .run_cycle_at(start + Duration::from_secs(1), Utc::now())
+            .await;
+        components_mock.assert_async().await;
+        sd_mock.assert_async().await;
+        assert!(reporter.state.is_open("env1/srvA"));
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_components_fetch_error() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let components_mock = server
+            .mock("GET", "/v2/components")
+            .with_body(
+                serde_json::json!([{"id": 5, "name": "Component A", "attributes": [
+                    {"name": "region", "value": "Reg1"}
+                ]}])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"components": [5]}),
+            ))
+            .expect(2)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        reporter.config.reporter.component_cache_ttl_secs = 60;
+        reporter.config.reporter.component_fetch_attempts = 1;
+        let start = Instant::now();
+        reporter.run_cycle_at(start, Utc::now()).await;
+        components_mock.assert_async().await;
+        reporter.state.mark_resolved("env1/srvA");
+
+        // Failing refresh keeps the previous cache
+        components_mock.remove_async().await;
+        let failing_mock = server
+            .mock("GET", "/v2/components")
+            .with_status(500)
+            .create_async()
+            .await;
+        reporter
+            .run_cycle_at(start + Duration::from_secs(60), Utc::now())
+            .await;
+        failing_mock.assert_async().await;
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_run_cycle_max_impact() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 5).await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"components": [5], "impact": 3}),
+            ))
+            .expect(2)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let start = Instant::now();
+
+        reporter.run_cycle_at(start, Utc::now()).await;
+        // Report again after the cooldown, the clamping is not warned about again
+        reporter
+            .run_cycle_at(start + Duration::from_secs(1), Utc::now())
+            .await;
+        sd_mock.assert_async().await;
+        logs_assert(|lines: &[&str]| {
+            match lines
+                .iter()
+                .filter(|line| line.contains("exceeds the Status Dashboard max_impact"))
+                .count()
+            {
+                1 => Ok(()),
+                n => Err(format!("expected a single warning, got {}", n)),
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_impact_map() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"components": [5], "impact": 5}),
+            ))
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter_with(server.url().as_str(), None, |config| {
+            config.status_dashboard.as_mut().unwrap().max_impact = 5;
+            config.reporter.impact_map = vec![
+                ImpactMapping {
+                    weight: 1,
+                    impact: 1,
+                },
+                ImpactMapping {
+                    weight: 2,
+                    impact: 5,
+                },
+            ];
+        });
+
+        reporter.run_cycle().await;
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_seconds_since_last_success() {
+        let mut server = mockito::Server::new_async().await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let failing = server
+            .mock("GET", "/api/v1/health")
+            .match_query(mockito::Matcher::Any)
+            .with_status(502)
+            .expect(3)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let start = Instant::now();
+
+        let mut staleness = Vec::new();
+        for cycle in 1..=3 {
+            let now = start + Duration::from_secs(60 * cycle);
+            reporter.run_cycle_at(now, Utc::now()).await;
+            staleness.push(reporter.seconds_since_last_success(now));
+        }
+        failing.assert_async().await;
+        assert!(staleness[0] >= 60, "{:?}", staleness);
+        assert!(
+            staleness.windows(2).all(|x| x[1] >= x[0] + 60),
+            "{:?}",
+            staleness
+        );
+
+        // Successful fetch resets the staleness
+        failing.remove_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 0).await;
+        let now = start + Duration::from_secs(240);
+        reporter.run_cycle_at(now, Utc::now()).await;
+        assert_eq!(reporter.seconds_since_last_success(now), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_maintenance() {
+        let mut server = mockito::Server::new_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 2).await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .expect(1)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        reporter.config.reporter.maintenance_windows = vec![MaintenanceWindow {
+            from: now - ChronoDuration::hours(1),
+            to: now + ChronoDuration::hours(1),
+            services: Some(vec!["srvA".to_string()]),
+        }];
+
+        reporter.run_cycle_at(Instant::now(), now).await;
+        assert!(!reporter.state.is_open("env1/srvA"));
+
+        // Past maintenance does not suppress the incident
+        reporter.config.reporter.maintenance_windows[0].to = now - ChronoDuration::minutes(1);
+        reporter.run_cycle_at(Instant::now(), now).await;
+        assert!(reporter.state.is_open("env1/srvA"));
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_sd_dry_run() {
+        let mut server = mockito::Server::new_async().await;
+        let convertor_mock = get_health_mock(&mut server, 2).await;
+        let sd_mock = server
+            .mock("POST", "/api/v1/component_status")
+            .expect(0)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        let sdb_config = reporter.config.status_dashboard.as_mut().unwrap();
+        sdb_config.api_version = StatusDashboardApi::V1;
+        sdb_config.dry_run = true;
+
+        reporter.run_cycle().await;
+        assert!(reporter.state.is_open("env1/srvA"));
+
+        // Neither creating nor resolving reaches the Status Dashboard
+        convertor_mock.remove_async().await;
+        let _convertor_mock = get_health_mock(&mut server, 0).await;
+        reporter.run_cycle().await;
+        assert!(!reporter.state.is_open("env1/srvA"));
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_dry_run() {
+        let mut server = mockito::Server::new_async().await;
+        let convertor_mock = get_health_mock(&mut server, 2).await;
+        let _components_mock = get_components_mock(&mut server).await;
+        let sd_mock = server
+            .mock("POST", "/v2/events")
+            .expect(0)
+            .create_async()
+            .await;
+        let mut reporter = get_test_reporter(server.url().as_str(), None);
+        reporter.dry_run = true;
+
+        reporter.run_cycle().await;
+        assert!(reporter.state.is_open("env1/srvA"));
+
+        convertor_mock.assert_async().await;
+        sd_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_cycle_auth_header() {
+        for (secret, header) in [
+            (
+                Some("secret"),
+                mockito::Matcher::Regex("^Bearer .+".to_string()),
+            ),
+            (None, mockito::Matcher::Missing),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let _convertor_mock = get_health_mock(&mut server, 1).await;
+            let components_mock = server
+                .mock("GET", "/v2/components")
+                .match_header("authorization", header.clone())
+                .with_body(
+                    serde_json::json!([{"id": 5, "name": "Component A", "attributes": [
+                        {"name": "region", "value": "Reg1"}
+                    ]}])
+                    .to_string(),
+                )
+                .create_async()
+                .await;
+            let sd_mock = server
+                .mock("POST", "/v2/events")
+                .match_header("authorization", header)
+                .create_async()
+                .await;
+            let mut reporter = get_test_reporter(server.url().as_str(), secret);
+
+            reporter.run_cycle().await;
+            components_mock.assert_async().await;
+            sd_mock.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_services_api_token() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/v1/health")
+            .match_query(mockito::Matcher::Any)
+            .match_header("authorization", "Bearer secret")
+            .with_body(serde_json::to_string(&get_response(&[0])).unwrap())
+            .create_async()
+            .await;
+
+        let res = check_services(
+            &reqwest::Client::new(),
+            &server.url(),
+            Some("secret"),
+            vec![("env1".to_string(), "srvA".to_string())],
+            1,
+        )
+        .await;
+        mock.assert_async().await;
+        assert!(res[0].2.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_services_batch() {
+        let mut server = mockito::Server::new_async().await;
+        let batch_mock = server
+            .mock("POST", "/api/v1/health/batch")
+            .match_header("authorization", "Bearer secret")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!([
+                {"environment": "env1", "service": "srvA", "from": "-5min", "to": "-2min"},
+                {"environment": "env1", "service": "srvX"}
+            ])))
+            .with_body(
+                serde_json::json!([
+                    get_response(&[1]),
+                    {"name": "srvX", "environment": "env1", "message": "Requested service not supported"}
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let single_mock = server
+            .mock("GET", "/api/v1/health")
+            .match_query(mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let res = check_services(
+            &reqwest::Client::new(),
+            &server.url(),
+            Some("secret"),
+            vec![
+                ("env1".to_string(), "srvA".to_string()),
+                ("env1".to_string(), "srvX".to_string()),
+            ],
+            2,
+        )
+        .await;
+        batch_mock.assert_async().await;
+        single_mock.assert_async().await;
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].1, "srvA");
+        assert_eq!(res[0].2.as_ref().unwrap().metrics[0].value, 1);
+        assert_eq!(res[1].1, "srvX");
+        assert_eq!(
+            res[1].2.as_ref().unwrap_err().to_string(),
+            "Convertor error: Requested service not supported"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_services_batch_chunks() {
+        let mut server = mockito::Server::new_async().await;
+        let batch_mock = server
+            .mock("POST", "/api/v1/health/batch")
+            .match_body(mockito::Matcher::Regex("srvA.*srvB".to_string()))
+            .with_body(serde_json::json!([get_response(&[1]), get_response(&[2])]).to_string())
+            .create_async()
+            .await;
+        // Second chunk fails and is queried one by one
+        let failing_batch_mock = server
+            .mock("POST", "/api/v1/health/batch")
+            .match_body(mockito::Matcher::Regex("srvC".to_string()))
+            .with_status(500)
+            .create_async()
+            .await;
+        let single_mock = server
+            .mock("GET", "/api/v1/health")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "service".to_string(),
+                "srvC".to_string(),
+            ))
+            .with_body(serde_json::to_string(&get_response(&[3])).unwrap())
+            .create_async()
+            .await;
+
+        let res = check_services(
+            &reqwest::Client::new(),
+            &server.url(),
+            None,
+            ["srvA", "srvB", "srvC"]
+                .iter()
+                .map(|x| ("env1".to_string(), x.to_string()))
+                .collect(),
+            2,
+        )
+        .await;
+        batch_mock.assert_async().await;
+        failing_batch_mock.assert_async().await;
+        single_mock.assert_async().await;
+        let values: Vec<(String, u8)> = res
+            .into_iter()
+            .map(|(_, service, res)| (service, res.unwrap().metrics[0].value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("srvA".to_string(), 1),
+                ("srvB".to_string(), 2),
+                ("srvC".to_string(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_component_name_fallback() {
+        let config = |require: bool| {
+            let mut config = get_reporter_config("https://sd", None);
+            add_test_service(&mut config, "srvB", None);
+            config.reporter.require_component_name = require;
+            config
+        };
+
+        let reporter = Reporter::new(config(true));
+        let env = &reporter.components["env1"];
+        assert_eq!(env["srvA"].name, "Component A");
+        assert!(!env.contains_key("srvB"));
+
+        let reporter = Reporter::new(config(false));
+        let env = &reporter.components["env1"];
+        assert_eq!(env["srvA"].name, "Component A");
+        assert_eq!(env["srvB"].name, "srvB");
+    }
+
+    #[test]
+    fn test_convertor_url() {
+        let config = |convertor_url: Option<&str>| {
+            let mut config = get_reporter_config("https://sd", None);
+            config.reporter.convertor_url = convertor_url.map(|x| x.to_string());
+            config
+        };
+
+        assert_eq!(
+            Reporter::new(config(None)).convertor_url,
+            "http://localhost:3005"
+        );
+        assert_eq!(
+            Reporter::new(config(Some("https://convertor.example:8443/"))).convertor_url,
+            "https://convertor.example:8443"
+        );
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let base = Duration::from_secs(2);
+        let max = Duration::from_secs(60);
+        let delays: Vec<Duration> = (0..8).map(|x| backoff_delay(x, base, max, 1.0)).collect();
+        assert_eq!(delays[0], Duration::from_secs(2));
+        assert_eq!(delays[1], Duration::from_secs(4));
+        assert_eq!(delays[4], Duration::from_secs(32));
+        assert!(delays.windows(2).all(|x| x[0] <= x[1]));
+        assert!(delays.iter().all(|x| *x <= max));
+        // Jitter randomizes up to one half of the delay
+        assert_eq!(backoff_delay(1, base, max, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff_delay(20, base, max, 0.0), Duration::from_secs(30));
+        assert_eq!(backoff_delay(u32::MAX, base, max, 1.0), max);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_components_with_retry() {
+        let mut server = mockito::Server::new_async().await;
+        let failure = server
+            .mock("GET", "/v2/components")
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let components = get_components_mock(&mut server).await;
+        let config = ReporterConfig {
+            component_fetch_attempts: 3,
+            component_fetch_backoff_secs: 0,
+            ..Default::default()
+        };
+
+        let res = fetch_components_with_retry(
+            &reqwest::Client::new(),
+            server.url().as_str(),
+            "/v2/components",
+            &HeaderMap::new(),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.len(), 1);
+        failure.assert_async().await;
+        components.assert_async().await;
+
+        // Giving up after the configured attempts
+        let mut server = mockito::Server::new_async().await;
+        let failure = server
+            .mock("GET", "/v2/components")
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let config = ReporterConfig {
+            component_fetch_attempts: 2,
+            component_fetch_backoff_secs: 0,
+            ..Default::default()
+        };
+        assert!(fetch_components_with_retry(
+            &reqwest::Client::new(),
+            server.url().as_str(),
+            "/v2/components",
+            &HeaderMap::new(),
+            &config,
+        )
+        .await
+        .is_err());
+        failure.assert_async().await;
+    }
+}
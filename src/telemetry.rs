@@ -0,0 +1,245 @@
+//! Telemetry of the convertor
+//!
+//! Prometheus metrics describing the convertor itself (not the processed metrics)
+//!
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::types::SharedState;
+
+/// Registry with the convertor metrics
+pub struct Telemetry {
+    pub registry: Registry,
+    /// Requests sent to Graphite
+    pub graphite_requests: IntCounter,
+    /// Failed requests to Graphite
+    pub graphite_errors: IntCounter,
+    /// Duration of Graphite requests
+    pub graphite_latency: Histogram,
+    /// Health API requests by service and environment
+    pub health_requests: IntCounterVec,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let graphite_requests = IntCounter::new(
+            "cloudmon_graphite_requests_total",
+            "Amount of requests sent to Graphite",
+        )
+        .unwrap();
+        let graphite_errors = IntCounter::new(
+            "cloudmon_graphite_errors_total",
+            "Amount of failed requests to Graphite",
+        )
+        .unwrap();
+        let graphite_latency = Histogram::with_opts(HistogramOpts::new(
+            "cloudmon_graphite_request_duration_seconds",
+            "Duration of requests to Graphite",
+        ))
+        .unwrap();
+        let health_requests = IntCounterVec::new(
+            Opts::new(
+                "cloudmon_health_requests_total",
+                "Amount of service health requests",
+            ),
+            &["service", "environment"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(graphite_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(graphite_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(graphite_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(health_requests.clone()))
+            .unwrap();
+        Self {
+            registry,
+            graphite_requests,
+            graphite_errors,
+            graphite_latency,
+            health_requests,
+        }
+    }
+
+    /// Render metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Construct telemetry routes
+pub fn get_telemetry_routes() -> Router<SharedState> {
+    Router::new().route("/metrics", get(handler_metrics))
+}
+
+/// Handler for the Prometheus metrics exposition
+pub async fn handler_metrics(State(state): State<SharedState>) -> impl IntoResponse {
+    match state.read().await.telemetry {
+        Some(ref telemetry) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+            telemetry.render(),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/render")
+            .match_query(mockito::Matcher::Any)
+            .with_body(json!([{"target": "srvA.metric-1", "datapoints": [[1.0, 1]]}]).to_string())
+            .create_async()
+            .await;
+        let f = format!(
+            "
+        datasource:
+          url: '{}'
+        server:
+          port: 3005
+        telemetry:
+          enabled: true
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+              - expression: 'srvA.metric-1'
+                weight: 1
+",
+            server.url()
+        );
+        let config = config::Config::from_config_str(f.as_str()).unwrap();
+        let mut state = types::AppState::new(config);
+        state.process_config().unwrap();
+        let app = api::v1::get_v1_routes()
+            .merge(telemetry::get_telemetry_routes())
+            .with_state(Arc::new(RwLock::new(state)));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/health?service=srvA&environment=env1&from=-5min&to=-2min")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains("cloudmon_graphite_requests_total 1"),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("cloudmon_graphite_errors_total 0"),
+            "{}",
+            body
+        );
+        assert!(body.contains("cloudmon_graphite_request_duration_seconds_count 1"));
+        assert!(body
+            .contains("cloudmon_health_requests_total{environment=\"env1\",service=\"srvA\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_unknown_labels() {
+        let state = test_utils::get_test_state_with("http://localhost:1", |config| {
+            config.telemetry.enabled = true
+        })
+        .unwrap();
+        let app = api::v1::get_v1_routes()
+            .merge(telemetry::get_telemetry_routes())
+            .with_state(Arc::new(RwLock::new(state)));
+
+        for uri in [
+            "/health?service=random1&environment=env1&from=-5min&to=-2min",
+            "/health?service=srvA&environment=random2&from=-5min&to=-2min",
+        ] {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let (status, body) = test_utils::get_text(app, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("random1"), "{}", body);
+        assert!(!body.contains("random2"), "{}", body);
+        assert!(
+            body.contains(
+                "cloudmon_health_requests_total{environment=\"unknown\",service=\"srvA\"} 1"
+            ),
+            "{}",
+            body
+        );
+    }
+}
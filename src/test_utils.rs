@@ -0,0 +1,134 @@
+//! Helpers shared by the unit tests
+//!
+use crate::*;
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tower::ServiceExt;
+
+/// Configuration of the convertor with srvA (compute) and srvB (network) health metrics in env1
+/// using the Graphite at `url`
+pub(crate) fn get_test_config(url: &str) -> config::Config {
+    let f = format!(
+        "
+    datasource:
+      url: '{}'
+    server:
+      port: 3005
+    metric_templates:
+      tmpl1:
+        query: dummy1($environment.$service.count)
+        op: lt
+        threshold: 90
+    environments:
+      - name: env1
+    flag_metrics:
+      - name: metric-1
+        service: srvA
+        template:
+          name: tmpl1
+        environments:
+          - name: env1
+      - name: metric-1
+        service: srvB
+        template:
+          name: tmpl1
+        environments:
+          - name: env1
+    health_metrics:
+      srvA:
+        service: srvA
+        component_name: Component A
+        category: compute
+        metrics:
+          - srvA.metric-1
+        expressions:
+          - expression: 'srvA.metric-1'
+            weight: 1
+      srvB:
+        service: srvB
+        category: network
+        metrics:
+          - srvB.metric-1
+        expressions:
+          - expression: 'srvB.metric-1'
+            weight: 2
+",
+        url
+    );
+    config::Config::from_config_str(f.as_str()).unwrap()
+}
+
+/// Add srvA metric-2 flag (tmpl2, gt 80) in the environments of srvA metric-1 to the test
+/// configuration (and to the srvA health metrics) and drop srvB
+pub(crate) fn add_test_metric_2(config: &mut config::Config) {
+    config
+        .metric_templates
+        .get_or_insert_with(HashMap::new)
+        .insert(
+            "tmpl2".to_string(),
+            types::BinaryMetricRawDef {
+                query: "dummy2($environment.$service.count)".to_string(),
+                op: types::CmpType::Gt,
+                threshold: 80.0,
+                ..Default::default()
+            },
+        );
+    config.flag_metrics.retain(|x| x.service == "srvA");
+    let mut metric2 = config.flag_metrics[0].clone();
+    metric2.name = "metric-2".to_string();
+    metric2.template.as_mut().unwrap().name = "tmpl2".to_string();
+    for env in metric2.environments.iter_mut() {
+        env.threshold = None;
+        env.epsilon = None;
+    }
+    config.flag_metrics.push(metric2);
+    config.health_metrics.remove("srvB");
+    let health = config.health_metrics.get_mut("srvA").unwrap();
+    health.component_name = None;
+    health.metrics.push("srvA.metric-2".to_string());
+}
+
+/// Processed state of the test configuration adjusted by `configure`
+pub(crate) fn get_test_state_with(
+    url: &str,
+    configure: impl FnOnce(&mut config::Config),
+) -> Result<types::AppState, types::CloudMonError> {
+    let mut config = get_test_config(url);
+    configure(&mut config);
+    let mut state = types::AppState::new(config);
+    state.process_config().map(|_| state)
+}
+
+/// State of the convertor with srvA (compute) and srvB (network) health metrics in env1 using
+/// the Graphite at `url`
+pub(crate) fn get_test_state(url: &str) -> types::SharedState {
+    Arc::new(RwLock::new(get_test_state_with(url, |_| {}).unwrap()))
+}
+
+/// GET the uri returning the status and JSON body
+pub(crate) async fn get_json(app: axum::Router, uri: &str) -> (StatusCode, Value) {
+    let response = app
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+/// GET the uri returning the status and text body
+pub(crate) async fn get_text(app: axum::Router, uri: &str) -> (StatusCode, String) {
+    let response = app
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
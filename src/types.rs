@@ -1,14 +1,24 @@
 //! CloudMon metrics processor types
 //!
 //! Internal types definitions
-use crate::config::Config;
+use crate::config::{Config, MissingMetrics, ValidationLevel};
+use crate::graphite::{GraphiteCache, MetricPath};
+use crate::telemetry::Telemetry;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use evalexpr::{build_operator_tree, Node};
 use new_string_template::template::Template;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashSet;
 use std::collections::{BTreeMap, HashMap};
-use std::fmt;
+use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::RwLock;
 
 use reqwest::ClientBuilder;
 
@@ -25,6 +35,24 @@ pub struct BinaryMetricRawDef {
     pub query: String,
     pub op: CmpType,
     pub threshold: f32,
+    /// Tolerance of the `eq` comparison
+    #[serde(default)]
+    pub epsilon: f32,
+    /// Query names its series itself and is not wrapped into `alias()`
+    #[serde(default)]
+    pub no_alias: bool,
+    /// Metric paths available as query variables of the same name
+    #[serde(default)]
+    pub paths: HashMap<String, MetricPath>,
+    /// Query of the series compared against instead of the static `threshold`
+    pub baseline: Option<String>,
+    /// Multiplier of the baseline value
+    #[serde(default = "default_baseline_factor")]
+    pub baseline_factor: f32,
+}
+
+fn default_baseline_factor() -> f32 {
+    1.0
 }
 
 impl Default for BinaryMetricRawDef {
@@ -33,6 +61,11 @@ impl Default for BinaryMetricRawDef {
             query: String::new(),
             op: CmpType::Lt,
             threshold: 0.0,
+            epsilon: 0.0,
+            no_alias: false,
+            paths: HashMap::new(),
+            baseline: None,
+            baseline_factor: default_baseline_factor(),
         }
     }
 }
@@ -55,6 +88,9 @@ pub struct MetricTemplateRef {
 pub struct EnvironmentDef {
     pub name: String,
     pub attributes: Option<HashMap<String, String>>,
+    /// Alternative names resolving to the same flag metrics (e.g. the name before a rename)
+    #[serde(default)]
+    pub aliases: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -62,6 +98,14 @@ pub struct FlagMetric {
     pub query: String,
     pub op: CmpType,
     pub threshold: f32,
+    /// Tolerance of the `eq` comparison
+    pub epsilon: f32,
+    /// Query is sent without the `alias()` wrapper
+    pub no_alias: bool,
+    /// Query of the baseline series replacing the static threshold
+    pub baseline: Option<String>,
+    /// Multiplier of the baseline value
+    pub baseline_factor: f32,
 }
 
 impl Default for FlagMetric {
@@ -70,14 +114,51 @@ impl Default for FlagMetric {
             query: String::new(),
             op: CmpType::Lt,
             threshold: 0.0,
+            epsilon: 0.0,
+            no_alias: false,
+            baseline: None,
+            baseline_factor: default_baseline_factor(),
         }
     }
 }
 
+/// How the result of the health expression is turned into the health value
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpressionMode {
+    /// Expression evaluates to a boolean, `weight` is used when it is true
+    #[default]
+    Boolean,
+    /// Expression evaluates to a number used as the weight (clamped to `0..=255`)
+    Numeric,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct MetricExpressionDef {
     pub expression: String,
-    pub weight: i32,
+    /// Weight of the boolean expression (required), ignored in the numeric mode
+    #[serde(default)]
+    pub weight: Option<i32>,
+    #[serde(default)]
+    pub mode: ExpressionMode,
+    /// Expression compiled during configuration processing
+    #[serde(skip)]
+    pub node: Option<Node>,
+    /// Expression as written in the configuration, `expression` holds the rewritten one
+    #[serde(skip)]
+    pub source: Option<String>,
+}
+
+impl MetricExpressionDef {
+    /// Weight of the expression, 0 when not set (numeric expressions)
+    pub fn weight_or_default(&self) -> i32 {
+        self.weight.unwrap_or_default()
+    }
+
+    /// Expression as configured, reported to the users
+    pub fn configured_expression(&self) -> &str {
+        self.source.as_deref().unwrap_or(self.expression.as_str())
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -85,13 +166,22 @@ pub struct FlagMetricDef {
     pub name: String,
     pub service: String,
     pub template: Option<MetricTemplateRef>,
+    /// Inline query used instead of the template
+    pub query: Option<String>,
+    pub op: Option<CmpType>,
+    pub threshold: Option<f32>,
     pub environments: Vec<MetricEnvironmentDef>,
+    /// Temporarily switched off metric, its configuration is ignored
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct MetricEnvironmentDef {
     pub name: String,
     pub threshold: Option<f32>,
+    /// Overrides `epsilon` of the template
+    pub epsilon: Option<f32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -101,6 +191,109 @@ pub struct ServiceHealthDef {
     pub category: String,
     pub metrics: Vec<String>,
     pub expressions: Vec<MetricExpressionDef>,
+    /// What to do when some of the metrics are not available in the environment
+    #[serde(default)]
+    pub missing_metrics: MissingMetrics,
+    /// Windows with fewer datapoints are reported healthy instead of evaluated
+    #[serde(default)]
+    pub min_datapoints: usize,
+    /// Temporarily switched off service health, it is neither exposed nor evaluated
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// Suffix of the expression variable holding the raw value of the metric
+pub const RAW_VALUE_SUFFIX: &str = "_value";
+
+/// Highest severity understood by the status dashboard (0 - ok, 1 - degraded, 2 - outage)
+pub const MAX_WEIGHT: i32 = 2;
+
+/// Substitute `$var`, `${var}` and `${var:-default}` variables of the template query. The
+/// default is used when the variable is not present in `vars`. Variables without a value are
+/// reported as error.
+pub fn render_query(query: &str, vars: &HashMap<&str, &str>) -> Result<String, String> {
+    let mut missing: Option<String> = None;
+    let braced_regex = Regex::new(r"\$\{([^\s:{}]+)(?::-([^}]*))?\}").unwrap();
+    let query = braced_regex.replace_all(query, |caps: &regex::Captures| {
+        match (vars.get(&caps[1]), caps.get(2)) {
+            (Some(value), _) => value.to_string(),
+            (None, Some(default)) => default.as_str().to_string(),
+            (None, None) => {
+                missing.get_or_insert_with(|| caps[1].to_string());
+                String::new()
+            }
+        }
+    });
+    // We substitute $var syntax
+    let var_regex = Regex::new(r"(?mi)\$(\w+)").unwrap();
+    if let Some(name) = missing.or_else(|| {
+        var_regex
+            .captures_iter(&query)
+            .map(|caps| caps[1].to_string())
+            .find(|name| !vars.contains_key(name.as_str()))
+    }) {
+        return Err(format!("undefined variable '{}'", name));
+    }
+    Template::new(query)
+        .with_regex(&var_regex)
+        .render(vars)
+        .map_err(|e| e.to_string())
+}
+
+/// Translate standalone `AND`/`OR`/`NOT` keywords of the expression into the `&&`/`||`/`!`
+/// operators understood by evalexpr. Keywords being part of identifiers are left untouched.
+pub fn normalize_expression(expression: &str) -> String {
+    let is_ident_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '-');
+    let mut result = String::with_capacity(expression.len());
+    let mut word = String::new();
+    let flush = |word: &mut String, result: &mut String| {
+        match word.as_str() {
+            "AND" => result.push_str("&&"),
+            "OR" => result.push_str("||"),
+            "NOT" => result.push('!'),
+            other => result.push_str(other),
+        }
+        word.clear();
+    };
+    for c in expression.chars() {
+        if is_ident_char(c) {
+            word.push(c);
+        } else {
+            flush(&mut word, &mut result);
+            result.push(c);
+        }
+    }
+    flush(&mut word, &mut result);
+    result
+}
+
+/// Return list of problems with the expression weights of the health metric: duplicates (first
+/// matching expression wins within the weight) and weights outside of `0..=MAX_WEIGHT`.
+pub fn check_expression_weights(name: &str, expressions: &[MetricExpressionDef]) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    let mut seen: HashSet<i32> = HashSet::new();
+    for expr in expressions
+        .iter()
+        .filter(|x| x.mode == ExpressionMode::Boolean)
+    {
+        // Missing weights are reported during the configuration processing
+        let Some(weight) = expr.weight else {
+            continue;
+        };
+        if !(0..=MAX_WEIGHT).contains(&weight) {
+            problems.push(format!(
+                "health metric {}: weight {} of expression '{}' is outside of 0..={}",
+                name, weight, expr.expression, MAX_WEIGHT
+            ));
+        }
+        if !seen.insert(weight) {
+            problems.push(format!(
+                "health metric {}: weight {} is used by multiple expressions",
+                name, weight
+            ));
+        }
+    }
+    problems
 }
 
 pub type MetricPoints = BTreeMap<u32, bool>;
@@ -110,38 +303,129 @@ pub struct MetricData {
     #[serde(rename(serialize = "datapoints"))]
     pub points: MetricPoints,
 }
-/// List of the service health values (ts, data)
-pub type ServiceHealthData = Vec<(u32, u8)>;
+/// Service health at the certain point in time
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ServiceHealthPoint {
+    /// Timestamp
+    pub ts: u32,
+    /// Health value (weight of the matched expression)
+    pub value: u8,
+    /// Flag metrics raised at this point
+    #[serde(default)]
+    pub triggered: Vec<String>,
+    /// Raw values of the raised flag metrics
+    #[serde(default)]
+    pub values: BTreeMap<String, f64>,
+    /// Expression which produced the value
+    #[serde(default)]
+    pub matched_expression: Option<String>,
+}
 
+/// List of the service health values
+pub type ServiceHealthData = Vec<ServiceHealthPoint>;
+/// Flag states (ts, flag) of the service flag metrics by metric name
+pub type ServiceFlagsData = BTreeMap<String, Vec<(u32, bool)>>;
+
+/// Result of a single health expression at the explained datapoint
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ExpressionExplanation {
+    pub expression: String,
+    pub weight: i32,
+    pub mode: ExpressionMode,
+    /// Whether the expression matched (numeric expressions when their value rounds above 0)
+    pub result: bool,
+    /// Value of the numeric expression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+}
+
+/// Health evaluation of the service at a single datapoint with every expression and the
+/// expression context
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HealthExplanation {
+    /// Timestamp of the datapoint (`None` when there is no data around the requested time)
+    pub ts: Option<u32>,
+    /// Health value of the datapoint
+    pub value: u8,
+    pub matched_expression: Option<String>,
+    pub expressions: Vec<ExpressionExplanation>,
+    /// Expression variables (flags and raw values) by name
+    pub context: BTreeMap<String, serde_json::Value>,
+}
+
+/// Reason of the failed datasource request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DatasourceErrorKind {
+    /// No response was received (connection failure, timeout)
+    NoResponse,
+    /// Datasource responded with an error status
+    Status,
+    /// Response of the datasource could not be parsed
+    InvalidResponse,
+}
+
+/// Errors of the metrics processing
+#[derive(Debug, Error)]
 pub enum CloudMonError {
+    #[error("Requested service not supported")]
     ServiceNotSupported,
+    #[error("Environment for service not supported")]
     EnvNotSupported,
+    #[error("Internal Expression evaluation error")]
     ExpressionError,
-    GraphiteError,
+    #[error("Graphite error: {message}")]
+    GraphiteError {
+        kind: DatasourceErrorKind,
+        /// HTTP status of the Graphite response (`None` when no response was received)
+        status: Option<u16>,
+        message: String,
+        /// Targets of the failed request
+        targets: Vec<String>,
+    },
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+    #[error("Invalid time specification: {0}")]
+    InvalidTime(String),
+    #[error("Status Dashboard error: {0}")]
+    StatusDashboardError(String),
+    #[error("Status Dashboard authorization error: {0}")]
+    StatusDashboardAuthError(String),
+    #[error("Convertor error: {0}")]
+    ConvertorError(String),
+    #[error("Query timed out")]
+    Timeout,
+    #[error("Internal error: {0}")]
+    InternalError(String),
 }
-impl std::error::Error for CloudMonError {}
 
-impl fmt::Display for CloudMonError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl CloudMonError {
+    /// HTTP status the error is reported with by the API
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            CloudMonError::ServiceNotSupported => write!(f, "Requested service not supported"),
-            CloudMonError::EnvNotSupported => write!(f, "Environment for service not supported"),
-            CloudMonError::ExpressionError => write!(f, "Internal Expression evaluation error"),
-            CloudMonError::GraphiteError => write!(f, "Graphite error"),
+            CloudMonError::InvalidTime(_) => StatusCode::BAD_REQUEST,
+            CloudMonError::ServiceNotSupported | CloudMonError::EnvNotSupported => {
+                StatusCode::CONFLICT
+            }
+            CloudMonError::GraphiteError { .. } => StatusCode::BAD_GATEWAY,
+            CloudMonError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
-impl fmt::Debug for CloudMonError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            CloudMonError::ServiceNotSupported => write!(f, "Requested service not supported"),
-            CloudMonError::EnvNotSupported => write!(f, "Environment for service not supported"),
-            CloudMonError::ExpressionError => write!(f, "Internal Expression evaluation error"),
-            CloudMonError::GraphiteError => write!(f, "Graphite error"),
-        }
+
+impl IntoResponse for CloudMonError {
+    fn into_response(self) -> Response {
+        (
+            self.status_code(),
+            Json(json!({ "message": self.to_string() })),
+        )
+            .into_response()
     }
 }
 
+/// Application state shared between request handlers and swapped on config reload
+pub type SharedState = Arc<RwLock<AppState>>;
+
 #[derive(Clone)]
 pub struct AppState {
     pub config: Config,
@@ -150,95 +434,333 @@ pub struct AppState {
     pub flag_metrics: HashMap<String, HashMap<String, FlagMetric>>,
     pub health_metrics: HashMap<String, ServiceHealthDef>,
     pub environments: Vec<EnvironmentDef>,
+    /// Environment alias => canonical environment name
+    pub environment_aliases: HashMap<String, String>,
     pub services: HashSet<String>,
+    pub graphite_cache: Arc<GraphiteCache>,
+    pub telemetry: Option<Arc<Telemetry>>,
 }
 
 impl AppState {
     pub fn new(config: Config) -> Self {
         let timeout = Duration::from_secs(config.datasource.timeout as u64);
+        let cache_ttl = Duration::from_secs(config.datasource.cache_ttl_secs as u64);
+        let telemetry = config.telemetry.enabled.then(|| Arc::new(Telemetry::new()));
 
         Self {
-            config: config,
+            config,
             metric_templates: HashMap::new(),
             flag_metrics: HashMap::new(),
             req_client: ClientBuilder::new().timeout(timeout).build().unwrap(),
             health_metrics: HashMap::new(),
             environments: Vec::new(),
+            environment_aliases: HashMap::new(),
             services: HashSet::new(),
+            graphite_cache: Arc::new(GraphiteCache::new(cache_ttl)),
+            telemetry,
+        }
+    }
+
+    pub fn process_config(&mut self) -> Result<(), CloudMonError> {
+        match self.collect_config_errors().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
     }
 
-    pub fn process_config(&mut self) {
-        // We substitute $var syntax
-        let custom_regex = Regex::new(r"(?mi)\$([^\.]+)").unwrap();
+    /// Validate configuration without starting anything. Returns all problems found in the
+    /// configuration using the same logic as [`AppState::process_config`].
+    pub fn validate_config(config: Config) -> Vec<String> {
+        let mut state = AppState::new(config);
+        state
+            .collect_config_errors()
+            .into_iter()
+            .map(|e| match e {
+                CloudMonError::ConfigError(msg) => msg,
+                other => other.to_string(),
+            })
+            .collect()
+    }
+
+    /// Process the configuration and collect every encountered error instead of stopping at
+    /// the first one.
+    fn collect_config_errors(&mut self) -> Vec<CloudMonError> {
+        let mut errors: Vec<CloudMonError> = Vec::new();
         if let Some(templates) = &self.config.metric_templates {
             self.metric_templates.clone_from(templates);
         }
+        for env in self.config.environments.iter() {
+            for alias in env.aliases.iter() {
+                if self.config.environments.iter().any(|x| &x.name == alias) {
+                    errors.push(CloudMonError::ConfigError(format!(
+                        "environment alias {} of {} is an environment name itself",
+                        alias, env.name
+                    )));
+                } else if let Some(other) = self
+                    .environment_aliases
+                    .insert(alias.clone(), env.name.clone())
+                {
+                    errors.push(CloudMonError::ConfigError(format!(
+                        "environment alias {} is used by both {} and {}",
+                        alias, other, env.name
+                    )));
+                }
+            }
+        }
         for metric_def in self.config.flag_metrics.iter() {
-            if let Some(tmpl_ref) = &metric_def.template {
-                let metric_name = format!("{}.{}", metric_def.service, metric_def.name);
-                self.flag_metrics
-                    .insert(metric_name.clone(), HashMap::new());
-                let tmpl = self.metric_templates.get(&tmpl_ref.name).unwrap();
-                let tmpl_query = Template::new(tmpl.query.clone()).with_regex(&custom_regex);
-                for env in metric_def.environments.iter() {
-                    let mut raw = FlagMetric::default();
-                    raw.op = tmpl.op.clone();
-                    raw.threshold = match env.threshold {
+            let metric_name = format!("{}.{}", metric_def.service, metric_def.name);
+            if metric_def.disabled {
+                tracing::info!("Flag metric {} is disabled", metric_name);
+                continue;
+            }
+            let inline = metric_def.query.is_some()
+                || metric_def.op.is_some()
+                || metric_def.threshold.is_some();
+            // Template (or the inline definition) of the metric, its description for error
+            // messages and custom variables
+            let (tmpl, source, custom_vars) = match (&metric_def.template, inline) {
+                (Some(_), true) => {
+                    errors.push(CloudMonError::ConfigError(format!(
+                        "flag metric {} defines both template and inline query",
+                        metric_name
+                    )));
+                    continue;
+                }
+                (Some(tmpl_ref), false) => match self.metric_templates.get(&tmpl_ref.name) {
+                    Some(tmpl) => (
+                        tmpl.clone(),
+                        format!("template {}", tmpl_ref.name),
+                        tmpl_ref.vars.as_ref(),
+                    ),
+                    None => {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "flag metric {} references unknown template {}",
+                            metric_name, tmpl_ref.name
+                        )));
+                        continue;
+                    }
+                },
+                (None, true) => match (&metric_def.query, &metric_def.op, metric_def.threshold) {
+                    (Some(query), Some(op), Some(threshold)) => (
+                        BinaryMetricRawDef {
+                            query: query.clone(),
+                            op: op.clone(),
+                            threshold,
+                            ..Default::default()
+                        },
+                        "query".to_string(),
+                        None,
+                    ),
+                    _ => {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "flag metric {} requires query, op and threshold",
+                            metric_name
+                        )));
+                        continue;
+                    }
+                },
+                (None, false) => {
+                    tracing::warn!(
+                        "Flag metric {} has neither template nor query, ignoring it",
+                        metric_name
+                    );
+                    self.services.insert(metric_def.service.clone());
+                    continue;
+                }
+            };
+            self.flag_metrics
+                .insert(metric_name.clone(), HashMap::new());
+            for env in metric_def.environments.iter() {
+                let mut raw = FlagMetric {
+                    op: tmpl.op.clone(),
+                    threshold: match env.threshold {
                         Some(x) => x,
-                        None => tmpl.threshold.clone(),
+                        None => tmpl.threshold,
+                    },
+                    epsilon: env.epsilon.unwrap_or(tmpl.epsilon),
+                    no_alias: tmpl.no_alias,
+                    baseline_factor: tmpl.baseline_factor,
+                    ..Default::default()
+                };
+                let mut vars: HashMap<&str, &str> = HashMap::from([
+                    ("service", metric_def.service.as_str()),
+                    ("environment", env.name.as_str()),
+                ]);
+                // Explicit variables of the template reference override the built-in ones
+                if let Some(custom_vars) = custom_vars {
+                    vars.extend(custom_vars.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+                }
+                let paths: Result<Vec<(&str, String)>, String> = tmpl
+                    .paths
+                    .iter()
+                    .map(|(name, path)| {
+                        render_query(path.to_string().as_str(), &vars)
+                            .map(|path| (name.as_str(), path))
+                    })
+                    .collect();
+                let paths = match paths {
+                    Ok(paths) => paths,
+                    Err(e) => {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "flag metric {} in environment {}: {} path references {}",
+                            metric_name, env.name, source, e
+                        )));
+                        continue;
+                    }
+                };
+                let mut vars = vars.clone();
+                vars.extend(paths.iter().map(|(name, path)| (*name, path.as_str())));
+                raw.query = match render_query(tmpl.query.as_str(), &vars) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "flag metric {} in environment {}: {} references {}",
+                            metric_name, env.name, source, e
+                        )));
+                        continue;
+                    }
+                };
+                if let Some(baseline) = &tmpl.baseline {
+                    raw.baseline = match render_query(baseline.as_str(), &vars) {
+                        Ok(query) => Some(query),
+                        Err(e) => {
+                            errors.push(CloudMonError::ConfigError(format!(
+                                "flag metric {} in environment {}: {} baseline references {}",
+                                metric_name, env.name, source, e
+                            )));
+                            continue;
+                        }
                     };
-                    let vars: HashMap<&str, &str> = HashMap::from([
-                        ("service", metric_def.service.as_str()),
-                        ("environment", env.name.as_str()),
-                    ]);
-                    raw.query = tmpl_query.render(&vars).unwrap();
-                    if let Some(x) = self.flag_metrics.get_mut(&metric_name) {
-                        x.insert(env.name.clone(), raw.clone());
-                    } else {
-                        tracing::error!("Metric processing failed");
+                }
+                if let Some(x) = self.flag_metrics.get_mut(&metric_name) {
+                    // Aliases of the environment share the same metric definition
+                    for (alias, _) in self
+                        .environment_aliases
+                        .iter()
+                        .filter(|(_, canonical)| **canonical == env.name)
+                    {
+                        x.insert(alias.clone(), raw.clone());
                     }
+                    x.insert(env.name.clone(), raw.clone());
+                } else {
+                    tracing::error!("Metric processing failed");
                 }
-            };
+            }
             self.services.insert(metric_def.service.clone());
         }
 
         for (metric_name, health_def) in self.config.health_metrics.iter() {
             tracing::debug!("{:?}", health_def);
+            if health_def.disabled {
+                tracing::info!("Health metric {} is disabled", metric_name);
+                continue;
+            }
             let mut int_metric = ServiceHealthDef {
                 service: health_def.service.clone(),
                 component_name: health_def.component_name.clone(),
                 category: health_def.category.clone(),
                 metrics: health_def.metrics.clone(),
                 expressions: Vec::new(),
+                missing_metrics: health_def.missing_metrics.clone(),
+                min_datapoints: health_def.min_datapoints,
+                disabled: false,
             };
             // If we have "-" in the metric name evalexpr will treat it as minus operation. In order to
             // avoid that replace "-" with "_" in the expression. Values will be renamed during
             // evaluation.
             let mut replacements: HashMap<String, String> = HashMap::new();
+            let mut variables: HashMap<String, &String> = HashMap::new();
             for metric in health_def.metrics.iter() {
+                match variables.insert(metric.replace('-', "_"), metric) {
+                    Some(other) if other != metric => {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "health metric {}: metrics '{}' and '{}' collide once '-' is replaced with '_' in expressions",
+                            metric_name, other, metric
+                        )));
+                    }
+                    _ => {}
+                }
                 if metric.contains("-") {
                     replacements.insert(metric.into(), metric.replace("-", "_"));
                 }
             }
+            let known_metrics: HashSet<String> = health_def
+                .metrics
+                .iter()
+                .map(|x| x.replace('-', "_"))
+                .flat_map(|x| [format!("{}{}", x, RAW_VALUE_SUFFIX), x])
+                .collect();
             for expr in health_def.expressions.iter() {
-                let mut expression = expr.expression.clone();
+                let mut expression = normalize_expression(expr.expression.as_str());
                 for (k, v) in replacements.iter() {
                     expression = expression.replace(k, v);
                 }
+                // Verify expression only references declared metrics
+                let tree = match build_operator_tree(expression.as_str()) {
+                    Ok(tree) => tree,
+                    Err(e) => {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "health metric {}: cannot parse expression '{}': {}",
+                            metric_name, expr.expression, e
+                        )));
+                        continue;
+                    }
+                };
+                for ident in tree.iter_variable_identifiers() {
+                    if !known_metrics.contains(ident) {
+                        errors.push(CloudMonError::ConfigError(format!(
+                            "health metric {}: expression '{}' references undeclared metric '{}'",
+                            metric_name, expr.expression, ident
+                        )));
+                    }
+                }
+                if expr.weight.is_none() && expr.mode == ExpressionMode::Boolean {
+                    errors.push(CloudMonError::ConfigError(format!(
+                        "health metric {}: boolean expression '{}' requires a weight",
+                        metric_name, expr.expression
+                    )));
+                    continue;
+                }
                 int_metric.expressions.push(MetricExpressionDef {
-                    expression: expression,
-                    weight: expr.weight,
+                    expression,
+                    weight: Some(expr.weight_or_default()),
+                    mode: expr.mode.clone(),
+                    node: Some(tree),
+                    source: Some(expr.expression.clone()),
                 });
             }
+            for problem in check_expression_weights(metric_name, &health_def.expressions) {
+                match self.config.weight_validation {
+                    ValidationLevel::Warn => tracing::warn!("{}", problem),
+                    ValidationLevel::Error => errors.push(CloudMonError::ConfigError(problem)),
+                }
+            }
             self.health_metrics.insert(metric_name.into(), int_metric);
         }
         self.environments = self.config.environments.clone();
+        errors
+    }
+
+    /// Re-read configuration from the file and replace the shared state with the newly processed
+    /// one. On failure the current state is kept untouched.
+    pub async fn reload(state: &SharedState, config_file: &str) -> Result<(), CloudMonError> {
+        let config =
+            Config::new(config_file).map_err(|e| CloudMonError::ConfigError(e.to_string()))?;
+        let mut new_state = AppState::new(config);
+        new_state.process_config()?;
+        let mut current = state.write().await;
+        if new_state.telemetry.is_some() && current.telemetry.is_some() {
+            // Keep collected telemetry across reloads
+            new_state.telemetry.clone_from(&current.telemetry);
+        }
+        *current = new_state;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::test_utils::get_test_state_with;
     use crate::*;
 
     #[test]
@@ -286,9 +808,9 @@ mod test {
               - expression: 'srvA.metric-1 || srvA.metric-2'
                 weight: 1
 ";
-        let config = config::Config::from_config_str(f);
+        let config = config::Config::from_config_str(f).unwrap();
         let mut state = types::AppState::new(config);
-        state.process_config();
+        state.process_config().unwrap();
 
         // Validate flag_metrics conversion
         let m1 = state
@@ -318,4 +840,634 @@ mod test {
             "srvA.metric_1 || srvA.metric_2"
         );
     }
+
+    #[test]
+    fn test_state_undeclared_expression_metric() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+              - expression: 'srvA.metric-1 || srvA.metric3'
+                weight: 1
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        let err = state.process_config().unwrap_err().to_string();
+        assert!(err.contains("srvA.metric3"), "{}", err);
+        assert!(err.contains("undeclared"), "{}", err);
+    }
+
+    #[test]
+    fn test_state_missing_template() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl_missing
+            environments:
+              - name: env1
+        health_metrics: {}
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        let err = state.process_config().unwrap_err().to_string();
+        assert!(err.contains("srvA.metric-1"), "{}", err);
+        assert!(err.contains("tmpl_missing"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_state_reload() {
+        let cfg1 = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        status_dashboard:
+          url: abc
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics: {}
+";
+        let cfg2 = format!(
+            "{}
+          - name: metric-2
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+",
+            cfg1.replace("        health_metrics: {}\n", "")
+        ) + "        health_metrics: {}\n";
+        let dir = tempfile::Builder::new().tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(&path, cfg1).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut state = types::AppState::new(config::Config::new(path).unwrap());
+        state.process_config().unwrap();
+        let state: types::SharedState = std::sync::Arc::new(tokio::sync::RwLock::new(state));
+        assert!(!state
+            .read()
+            .await
+            .flag_metrics
+            .contains_key("srvA.metric-2"));
+
+        // Broken config keeps the old state
+        std::fs::write(path, "datasource: [").unwrap();
+        assert!(types::AppState::reload(&state, path).await.is_err());
+        assert!(state
+            .read()
+            .await
+            .flag_metrics
+            .contains_key("srvA.metric-1"));
+
+        std::fs::write(path, cfg2).unwrap();
+        types::AppState::reload(&state, path).await.unwrap();
+        assert!(state
+            .read()
+            .await
+            .flag_metrics
+            .contains_key("srvA.metric-2"));
+    }
+
+    #[test]
+    fn test_state_expression_weights() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        weight_validation: error
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+              - expression: 'srvA.metric-1'
+                weight: 1
+              - expression: '!srvA.metric-1'
+                weight: 1
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        let err = state.process_config().unwrap_err().to_string();
+        assert!(
+            err.contains("weight 1 is used by multiple expressions"),
+            "{}",
+            err
+        );
+
+        // Warning mode accepts the configuration
+        let config =
+            config::Config::from_config_str(&f.replace("weight_validation: error", "")).unwrap();
+        let mut state = types::AppState::new(config);
+        state.process_config().unwrap();
+
+        let problems = types::check_expression_weights(
+            "srvA",
+            &[types::MetricExpressionDef {
+                expression: "a".to_string(),
+                weight: Some(5),
+                mode: types::ExpressionMode::Boolean,
+                node: None,
+                source: None,
+            }],
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("outside of 0..=2"), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_state_expression_missing_weight() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        environments:
+          - name: env1
+        flag_metrics: []
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+            expressions:
+              - expression: 'srvA.metric-1'
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        let err = state.process_config().unwrap_err().to_string();
+        assert!(
+            err.contains("boolean expression 'srvA.metric-1' requires a weight"),
+            "{}",
+            err
+        );
+
+        // Numeric expressions do not need the weight
+        let numeric = f.replace(
+            "- expression: 'srvA.metric-1'",
+            "- expression: 'if(srvA.metric-1, 2, 0)'\n                mode: numeric",
+        );
+        let config = config::Config::from_config_str(&numeric).unwrap();
+        let mut state = types::AppState::new(config);
+        state.process_config().unwrap();
+        assert_eq!(state.health_metrics["srvA"].expressions[0].weight, Some(0));
+    }
+
+    #[test]
+    fn test_normalize_expression() {
+        assert_eq!(types::normalize_expression("a AND b"), "a && b");
+        assert_eq!(types::normalize_expression("a OR NOT b"), "a || ! b");
+        assert_eq!(
+            types::normalize_expression("(srv.a OR srv.b) AND NOT(srv.c)"),
+            "(srv.a || srv.b) && !(srv.c)"
+        );
+        assert_eq!(
+            types::normalize_expression("band_width AND srv.NOT-OR"),
+            "band_width && srv.NOT-OR"
+        );
+        assert_eq!(types::normalize_expression("a && !b"), "a && !b");
+    }
+
+    #[test]
+    fn test_metric_name_collision() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+          - name: metric_1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics:
+          srvA:
+            service: srvA
+            category: compute
+            metrics:
+              - srvA.metric-1
+              - srvA.metric_1
+            expressions:
+              - expression: 'srvA.metric_1'
+                weight: 1
+        status_dashboard:
+          url: 'https://sd'
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        let err = state.process_config().unwrap_err().to_string();
+        assert!(
+            err.contains(
+                "health metric srvA: metrics 'srvA.metric-1' and 'srvA.metric_1' collide once '-' is replaced with '_' in expressions"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_render_query_defaults() {
+        let vars: std::collections::HashMap<&str, &str> =
+            std::collections::HashMap::from([("service", "srvA"), ("environment", "env1")]);
+        assert_eq!(
+            types::render_query("sum($environment.${region:-global}.$service.count)", &vars)
+                .unwrap(),
+            "sum(env1.global.srvA.count)"
+        );
+
+        let vars: std::collections::HashMap<&str, &str> = std::collections::HashMap::from([
+            ("service", "srvA"),
+            ("environment", "env1"),
+            ("region", "eu-de"),
+        ]);
+        assert_eq!(
+            types::render_query("sum($environment.${region:-global}.$service.count)", &vars)
+                .unwrap(),
+            "sum(env1.eu-de.srvA.count)"
+        );
+        assert_eq!(
+            types::render_query("${environment:-}.$service", &vars).unwrap(),
+            "env1.srvA"
+        );
+    }
+
+    #[test]
+    fn test_template_custom_vars() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$cluster.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+              vars:
+                cluster: c1
+            environments:
+              - name: env1
+          - name: metric-2
+            service: srvA
+            template:
+              name: tmpl1
+              vars:
+                cluster: c2
+                service: other
+            environments:
+              - name: env1
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        state.process_config().unwrap();
+        assert_eq!(
+            state.flag_metrics["srvA.metric-1"]["env1"].query,
+            "dummy1(env1.c1.srvA.count)"
+        );
+        assert_eq!(
+            state.flag_metrics["srvA.metric-2"]["env1"].query,
+            "dummy1(env1.c2.other.count)"
+        );
+    }
+
+    #[test]
+    fn test_template_paths() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          api_down:
+            paths:
+              failed:
+                namespace: stats.counters.openstack.api
+                status: failed
+              attempted:
+                namespace: stats.counters.openstack.api
+                status: attempted
+            query: asPercent(sumSeries($failed), sumSeries($attempted))
+            op: eq
+            threshold: 100
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: api_down
+            service: srvA
+            template:
+              name: api_down
+            environments:
+              - name: env1
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        state.process_config().unwrap();
+        assert_eq!(
+            state.flag_metrics["srvA.api_down"]["env1"].query,
+            "asPercent(sumSeries(stats.counters.openstack.api.env1.*.srvA.*.*.failed.count), \
+             sumSeries(stats.counters.openstack.api.env1.*.srvA.*.*.attempted.count))"
+        );
+    }
+
+    #[test]
+    fn test_environment_aliases() {
+        let aliases = |environments: &[(&str, &[&str])]| {
+            let environments: Vec<types::EnvironmentDef> = environments
+                .iter()
+                .map(|(name, aliases)| types::EnvironmentDef {
+                    name: name.to_string(),
+                    attributes: None,
+                    aliases: aliases.iter().map(|x| x.to_string()).collect(),
+                })
+                .collect();
+            get_test_state_with("https://a.b", |config| config.environments = environments)
+        };
+
+        let state = aliases(&[("env1", &["old1"])]).unwrap();
+        let metric = &state.flag_metrics["srvA.metric-1"];
+        assert_eq!(metric["old1"].query, metric["env1"].query);
+        assert_eq!(state.environment_aliases["old1"], "env1");
+
+        assert!(matches!(
+            aliases(&[("env1", &["env2"]), ("env2", &[])]),
+            Err(types::CloudMonError::ConfigError(msg)) if msg.contains("is an environment name")
+        ));
+        assert!(matches!(
+            aliases(&[("env1", &["old"]), ("env2", &["old"])]),
+            Err(types::CloudMonError::ConfigError(msg)) if msg.contains("used by both")
+        ));
+    }
+
+    #[test]
+    fn test_flag_metric_inline() {
+        let inline = |template: bool, query: &str, op_threshold: Option<(types::CmpType, f32)>| {
+            get_test_state_with("https://a.b", |config| {
+                let metric = &mut config.flag_metrics[0];
+                if !template {
+                    metric.template = None;
+                }
+                metric.query = Some(query.to_string());
+                metric.op = op_threshold.as_ref().map(|x| x.0.clone());
+                metric.threshold = op_threshold.map(|x| x.1);
+                metric.environments[0].threshold = Some(5.0);
+            })
+        };
+
+        let state = inline(
+            false,
+            "inline($environment.$service)",
+            Some((types::CmpType::Gt, 10.0)),
+        )
+        .unwrap();
+        let metric = &state.flag_metrics["srvA.metric-1"]["env1"];
+        assert_eq!(metric.query, "inline(env1.srvA)");
+        assert_eq!(metric.op, types::CmpType::Gt);
+        // Environment threshold overrides the inline one
+        assert_eq!(metric.threshold, 5.0);
+
+        assert!(matches!(
+            inline(true, "inline()", None),
+            Err(types::CloudMonError::ConfigError(msg)) if msg.contains("both template and inline")
+        ));
+        assert!(matches!(
+            inline(false, "inline()", None),
+            Err(types::CloudMonError::ConfigError(msg)) if msg.contains("requires query, op and threshold")
+        ));
+    }
+
+    #[test]
+    fn test_template_missing_var() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1($environment.$missing.$service.count)
+            op: lt
+            threshold: 90
+        environments:
+          - name: env1
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+        health_metrics: {}
+        status_dashboard:
+          url: 'https://sd'
+";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        let err = state.process_config().unwrap_err().to_string();
+        assert!(
+            err.contains(
+                "flag metric srvA.metric-1 in environment env1: template tmpl1 references undefined variable 'missing'"
+            ),
+            "{}",
+            err
+        );
+
+        let vars: std::collections::HashMap<&str, &str> =
+            std::collections::HashMap::from([("service", "srvA")]);
+        assert_eq!(
+            types::render_query("sum(${service}.${region})", &vars).unwrap_err(),
+            "undefined variable 'region'"
+        );
+        assert_eq!(
+            types::render_query("sum(${service}.count)", &vars).unwrap(),
+            "sum(srvA.count)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        use axum::{http::StatusCode, response::IntoResponse};
+
+        for (error, status, message) in [
+            (
+                types::CloudMonError::ServiceNotSupported,
+                StatusCode::CONFLICT,
+                "Requested service not supported",
+            ),
+            (
+                types::CloudMonError::EnvNotSupported,
+                StatusCode::CONFLICT,
+                "Environment for service not supported",
+            ),
+            (
+                types::CloudMonError::ExpressionError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal Expression evaluation error",
+            ),
+            (
+                types::CloudMonError::GraphiteError {
+                    kind: types::DatasourceErrorKind::Status,
+                    status: Some(500),
+                    message: "datasource request failed (status 500)".to_string(),
+                    targets: vec!["alias(a.b,'x')".to_string()],
+                },
+                StatusCode::BAD_GATEWAY,
+                "Graphite error: datasource request failed (status 500)",
+            ),
+            (
+                types::CloudMonError::Timeout,
+                StatusCode::GATEWAY_TIMEOUT,
+                "Query timed out",
+            ),
+            (
+                types::CloudMonError::InvalidTime("yesterday".to_string()),
+                StatusCode::BAD_REQUEST,
+                "Invalid time specification: yesterday",
+            ),
+            (
+                types::CloudMonError::ConfigError("broken".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Configuration error: broken",
+            ),
+            (
+                types::CloudMonError::InternalError("broken".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal error: broken",
+            ),
+        ] {
+            let response = error.into_response();
+            assert_eq!(response.status(), status, "{}", message);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, serde_json::json!({ "message": message }));
+        }
+    }
+
+    #[test]
+    fn test_eq_epsilon_config() {
+        let f = "
+        datasource:
+          url: 'https:/a.b'
+        server:
+          port: 3005
+        metric_templates:
+          tmpl1:
+            query: dummy1
+            op: eq
+            threshold: 100
+            epsilon: 0.5
+        environments:
+          - name: env1
+          - name: env2
+        flag_metrics:
+          - name: metric-1
+            service: srvA
+            template:
+              name: tmpl1
+            environments:
+              - name: env1
+              - name: env2
+                epsilon: 0.1
+        health_metrics: {}
+        ";
+        let config = config::Config::from_config_str(f).unwrap();
+        let mut state = types::AppState::new(config);
+        state.process_config().unwrap();
+        let metric = state.flag_metrics.get("srvA.metric-1").unwrap();
+        assert_eq!(metric.get("env1").unwrap().epsilon, 0.5);
+        assert_eq!(metric.get("env2").unwrap().epsilon, 0.1);
+    }
 }
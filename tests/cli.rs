@@ -0,0 +1,121 @@
+//! Command line behaviour of the binaries
+use std::process::Command;
+
+/// Missing configuration file is reported and the binary exits with an error
+#[test]
+fn test_missing_config() {
+    for bin in [
+        env!("CARGO_BIN_EXE_cloudmon-metrics-convertor"),
+        env!("CARGO_BIN_EXE_cloudmon-metrics-reporter"),
+    ] {
+        let output = Command::new(bin)
+            .args(["--config", "/nonexistent/config.yaml"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("/nonexistent/config.yaml"), "{}", stdout);
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+    }
+}
+
+/// Configuration is validated without starting the convertor
+#[test]
+fn test_convertor_check_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("config.yaml");
+    let write_config = |expression: &str| {
+        std::fs::write(
+            &config,
+            format!(
+                "
+datasource:
+  url: 'https://a.b'
+server:
+  port: 3005
+metric_templates:
+  tmpl1:
+    query: dummy1($environment.$service.count)
+    op: lt
+    threshold: 90
+environments:
+  - name: env1
+flag_metrics:
+  - name: metric-1
+    service: srvA
+    template:
+      name: tmpl1
+    environments:
+      - name: env1
+health_metrics:
+  srvA:
+    service: srvA
+    category: compute
+    metrics:
+      - srvA.metric-1
+    expressions:
+      - expression: '{}'
+        weight: 1
+",
+                expression
+            ),
+        )
+        .unwrap();
+    };
+    let check = || {
+        Command::new(env!("CARGO_BIN_EXE_cloudmon-metrics-convertor"))
+            .args(["--config", config.to_str().unwrap(), "--check-config"])
+            .output()
+            .unwrap()
+    };
+
+    write_config("srvA.metric-1");
+    let output = check();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(
+        stdout.contains("1 services, 1 flag metrics, 1 environments"),
+        "{}",
+        stdout
+    );
+
+    write_config("srvA.unknown");
+    let output = check();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(
+        stdout.contains("srvA_unknown") || stdout.contains("srvA.unknown"),
+        "{}",
+        stdout
+    );
+}
+
+/// RS256 signing without a private key is refused on reporter startup
+#[test]
+fn test_reporter_rs256_without_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("config.yaml");
+    std::fs::write(
+        &config,
+        "
+datasource:
+  url: 'https://a.b'
+server:
+  port: 3005
+environments: []
+flag_metrics: []
+health_metrics: {}
+status_dashboard:
+  url: 'https://c.d'
+  jwt_algorithm: RS256
+",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cloudmon-metrics-reporter"))
+        .args(["--config", config.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("private_key_path"), "{}", stdout);
+}